@@ -0,0 +1,153 @@
+// what hashing costs on each backend. criterion saves every run under target/criterion and compares it with
+// the one before, so a regression shows up as a percentage. a filter argument picks benches by part of their
+// name: `cargo bench -- sha256`.
+
+use std::hint::black_box;
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use ed25519_dalek::SigningKey;
+use toy_blockchain::block::Block;
+use toy_blockchain::ledger::Output;
+use toy_blockchain::miner::{CpuMiner, GENERATION_STRIDE, Miner};
+use toy_blockchain::simd::{Job, Kernel};
+use toy_blockchain::transaction::Transaction;
+use toy_blockchain::util::{self, Hashing};
+
+// a block like the ones `mine` makes: a coinbase and a few payments
+fn block() -> Block {
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let mut transactions = vec![Transaction::coinbase(1, 1000, &key)];
+    transactions.extend((0..3).map(|number| {
+        let output = Output {
+            recipient: "Alice".to_string(),
+            value: 100 + number,
+        };
+        Transaction::payment(number + 2, vec![], vec![output], 1, &key)
+    }));
+    Block::new([0x1234_5678; 8], 7, transactions)
+}
+
+fn sha256(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sha256");
+    for blocks in [1, 4, 16] {
+        // 9 bytes of padding at least
+        let words = util::to_words(&util::pad(&vec![0xab; blocks * 64 - 9]));
+        group.throughput(Throughput::Elements(blocks as u64));
+        group.bench_with_input(BenchmarkId::new("blocks", blocks), &words, |b, words| {
+            b.iter(|| util::sha256(black_box(words)))
+        });
+    }
+    group.finish();
+}
+
+fn cpu_miner(c: &mut Criterion) {
+    let block = block();
+    let words = block.words();
+    let nonce_index = block.nonce_offset();
+    let mut group = c.benchmark_group("cpu miner");
+
+    group.throughput(Throughput::Elements(1));
+    let mut nonce = 0;
+    group.bench_function("hash_with_nonce", |b| {
+        b.iter(|| {
+            nonce += 1;
+            util::hash_with_nonce(black_box(&words), nonce_index, nonce)
+        })
+    });
+
+    // one thread, at a k nothing meets
+    group.throughput(Throughput::Elements(1024));
+    let job = Job::new(&words, nonce_index, Hashing::Sha256);
+    for kernel in Kernel::available() {
+        let mut start = 0;
+        group.bench_function(format!("{kernel:?}"), |b| {
+            b.iter(|| {
+                start += 1024;
+                job.search(kernel, start - 1024..start, 64)
+            })
+        });
+    }
+    // the same on the fastest kernel, with the digest hashed again
+    let job = Job::new(&words, nonce_index, Hashing::Sha256d);
+    let kernel = Kernel::detect();
+    let mut start = 0;
+    group.bench_function(format!("{kernel:?} sha256d"), |b| {
+        b.iter(|| {
+            start += 1024;
+            job.search(kernel, start - 1024..start, 64)
+        })
+    });
+
+    // a whole generation on every core, at a k nothing meets. each one takes a while, so fewer samples.
+    group.throughput(Throughput::Elements(GENERATION_STRIDE));
+    group.sample_size(10);
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut generation = 0;
+    group.bench_function("all threads", |b| {
+        b.iter(|| {
+            generation += 1;
+            runtime.block_on(CpuMiner.search(&words, nonce_index, 64, Hashing::Sha256, generation))
+        })
+    });
+    group.finish();
+}
+
+fn transactions(c: &mut Criterion) {
+    let block = block();
+    let transaction = &block.transactions()[1];
+    c.bench_function("transaction/to_string", |b| {
+        b.iter(|| black_box(transaction).to_string())
+    });
+    c.bench_function("transaction/json", |b| {
+        b.iter(|| serde_json::to_vec(black_box(transaction)).unwrap())
+    });
+    c.bench_function("block/nonce_offset", |b| {
+        b.iter(|| black_box(&block).nonce_offset())
+    });
+    c.bench_function("block/words", |b| b.iter(|| black_box(&block).words()));
+}
+
+// a dispatch of the default work group counts, on the first device, in each hashing with the shader's early
+// exit and with the full digest. skipped without a device.
+#[cfg(feature = "gpu")]
+fn gpu(c: &mut Criterion) {
+    use toy_blockchain::context::Context;
+    use toy_blockchain::miner::WORK_GROUP_COUNTS;
+
+    if vulkano::VulkanLibrary::new().is_err() {
+        println!("gpu benches skipped, no vulkan");
+        return;
+    }
+    let block = block();
+    let words = block.words();
+    let nonce_index = block.nonce_offset();
+    let mut context = match Context::on_device(&words, None, WORK_GROUP_COUNTS) {
+        Ok(context) => context,
+        Err(error) => {
+            println!("gpu benches skipped, {error}");
+            return;
+        }
+    };
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("gpu");
+    group.throughput(Throughput::Elements(GENERATION_STRIDE));
+    for hashing in [Hashing::Sha256, Hashing::Sha256d] {
+        for (name, early_exit) in [("early exit", true), ("full digest", false)] {
+            context.set_early_exit(early_exit);
+            let mut generation = 0;
+            group.bench_function(BenchmarkId::new(name, hashing), |b| {
+                b.iter(|| {
+                    generation += 1;
+                    runtime.block_on(context.search(&words, nonce_index, 64, hashing, generation))
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+#[cfg(feature = "gpu")]
+criterion_group!(benches, sha256, cpu_miner, transactions, gpu);
+#[cfg(not(feature = "gpu"))]
+criterion_group!(benches, sha256, cpu_miner, transactions);
+criterion_main!(benches);
@@ -0,0 +1,130 @@
+// compiles the slang shaders to spir-v with slangc when it's installed. without it the committed spir-v
+// next to each shader is used. either way the committed spir-v has to be there and compiled from the
+// current sources, or the build fails, so a tree never builds from spir-v that doesn't match its shaders.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use sha2::{Digest, Sha256};
+
+struct Shader {
+    // the shader first, then the modules it imports
+    sources: &'static [&'static str],
+    spirv: &'static str,
+    // sha256 of the sources the committed spir-v was compiled from
+    stamp: &'static str,
+}
+
+const SHADERS: [Shader; 2] = [
+    Shader {
+        sources: &[
+            "shaders/specialized.slang",
+            "shaders/sha256.slang",
+            "shaders/sha256d.slang",
+        ],
+        spirv: "shaders/specialized.spirv",
+        stamp: "shaders/specialized.spirv.sha256",
+    },
+    Shader {
+        sources: &["shaders/self_test.slang", "shaders/sha256.slang"],
+        spirv: "shaders/self_test.spirv",
+        stamp: "shaders/self_test.spirv.sha256",
+    },
+];
+// points at slangc if it isn't on the path
+const SLANGC_VAR: &str = "SLANGC";
+// set to write what slangc compiled back to the committed spir-v and its stamp
+const REFRESH_VAR: &str = "TOY_BLOCKCHAIN_REFRESH_SPIRV";
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    for shader in &SHADERS {
+        for path in shader.sources.iter().chain([&shader.spirv, &shader.stamp]) {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+    println!("cargo:rerun-if-env-changed={SLANGC_VAR}");
+    println!("cargo:rerun-if-env-changed={REFRESH_VAR}");
+    // nothing to compile for a cpu only build
+    if env::var_os("CARGO_FEATURE_GPU").is_none() {
+        return;
+    }
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("cargo sets OUT_DIR"));
+    let slangc = env::var(SLANGC_VAR).unwrap_or_else(|_| "slangc".to_string());
+    for shader in &SHADERS {
+        build(shader, &slangc, &out_dir);
+    }
+}
+
+fn build(shader: &Shader, slangc: &str, out_dir: &Path) {
+    let Shader {
+        sources,
+        spirv,
+        stamp,
+    } = *shader;
+    let out = out_dir.join(
+        Path::new(spirv)
+            .file_name()
+            .expect("spir-v paths are files"),
+    );
+    let digest = sources_digest(sources);
+    let current = fs::read_to_string(stamp).is_ok_and(|stamp| stamp.trim() == digest)
+        && Path::new(spirv).exists();
+    match compile(slangc, sources[0], &out) {
+        Ok(()) if env::var_os(REFRESH_VAR).is_some() => {
+            fs::copy(&out, spirv).expect("failed to refresh the committed spir-v");
+            fs::write(stamp, format!("{digest}\n")).expect("failed to write the spir-v stamp");
+        }
+        Ok(()) => {
+            if !current {
+                panic!(
+                    "{spirv} is missing or older than its sources. build once with {REFRESH_VAR}=1 and commit \
+                     it with its stamp"
+                );
+            }
+        }
+        // no slangc, fall back to what's committed
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            if !current {
+                panic!(
+                    "{spirv} is missing or older than its sources and there is no slangc to rebuild it. install \
+                     slangc (or point {SLANGC_VAR} at it) and build once with {REFRESH_VAR}=1"
+                );
+            }
+            fs::copy(spirv, &out).expect("failed to copy the committed spir-v");
+        }
+        Err(error) => panic!("failed to compile {} with {slangc}: {error}", sources[0]),
+    }
+}
+
+// over the sources in order, with their names so moving code between them counts as a change
+fn sources_digest(sources: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for path in sources {
+        let source = fs::read(path).unwrap_or_else(|error| panic!("{path}: {error}"));
+        hasher.update(path.as_bytes());
+        hasher.update((source.len() as u64).to_le_bytes());
+        hasher.update(&source);
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn compile(slangc: &str, source: &str, out: &Path) -> io::Result<()> {
+    let output = Command::new(slangc)
+        .args([
+            source, "-I", "shaders", "-target", "spirv", "-stage", "compute",
+        ])
+        .args(["-entry", "main", "-o"])
+        .arg(out)
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::transaction::Transaction;
+use crate::util::{self, Hashing};
+
+// the unit that gets mined. its serialization keeps the coursework layout, with the transactions taking
+// the place of the single transaction string.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Block {
+    // number of leading zero nibbles the hash was mined for
+    pub(crate) k: u32,
+    pub(crate) transactions: Vec<Transaction>,
+    pub(crate) iv: [u32; 8],
+    pub(crate) nonce: u64,
+    // the chain's, left out of the json of plain sha256 blocks so they keep the old layout
+    #[serde(default, skip_serializing_if = "Hashing::is_sha256")]
+    pub(crate) hashing: Hashing,
+}
+
+impl Block {
+    pub fn new(iv: [u32; 8], k: u32, transactions: Vec<Transaction>) -> Self {
+        Self {
+            k,
+            transactions,
+            iv,
+            nonce: u64::MAX,
+            hashing: Hashing::Sha256,
+        }
+    }
+
+    // the same block, hashed the way `hashing` says
+    pub fn with_hashing(self, hashing: Hashing) -> Self {
+        Self { hashing, ..self }
+    }
+
+    pub fn k(&self) -> u32 {
+        self.k
+    }
+
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    // hash of the block this one builds on, all zeros for a genesis block
+    pub fn iv(&self) -> [u32; 8] {
+        self.iv
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn hashing(&self) -> Hashing {
+        self.hashing
+    }
+
+    pub fn with_nonce(&self, nonce: u64) -> Self {
+        Self {
+            nonce,
+            ..self.clone()
+        }
+    }
+
+    fn transaction_string(&self) -> String {
+        self.transactions
+            .iter()
+            .map(|transaction| transaction.to_string())
+            .collect()
+    }
+
+    // NOTE: this SHOULD give the correct nonce offset. might have to look at it again if things start going wrong.
+    pub fn nonce_offset(&self) -> u32 {
+        let transaction_string = self.transaction_string();
+        let length = transaction_string.len();
+        let pad_length = util::calculate_manual_pad(length);
+        let pre_nonce_string = format!(
+            "{transaction_string:<pad_length$}{length:16}{iv0:08x}{iv1:08x}{iv2:08x}{iv3:08x}{iv4:08x}{iv5:08x}{iv6:08x}{iv7:08x}",
+            iv0 = self.iv[0],
+            iv1 = self.iv[1],
+            iv2 = self.iv[2],
+            iv3 = self.iv[3],
+            iv4 = self.iv[4],
+            iv5 = self.iv[5],
+            iv6 = self.iv[6],
+            iv7 = self.iv[7],
+        );
+        let word_idx = pre_nonce_string.len() / 4;
+        word_idx as u32
+    }
+
+    // the words the gpu mines on: the serialization with a placeholder nonce, which the shader overwrites
+    pub fn words(&self) -> Vec<u32> {
+        let padded = util::pad(self.with_nonce(u64::MAX).to_string().as_bytes());
+        util::to_words(&padded)
+    }
+
+    pub fn hash(&self) -> [u32; 8] {
+        self.hashing
+            .hash_with_nonce(&self.words(), self.nonce_offset(), self.nonce)
+    }
+
+    pub fn meets_target(&self) -> bool {
+        util::hash_meets_k(&self.hash(), self.k)
+    }
+}
+
+impl Display for Block {
+    // NOTE: this SHOULD give the properly padded string. might have to look at it again if things start going wrong.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let transaction_string = self.transaction_string();
+        let length = transaction_string.len();
+        let pad_length = util::calculate_manual_pad(length);
+        let transaction_string = format!(
+            "{transaction_string:<pad_length$}{length:<16}{iv0:08x}{iv1:08x}{iv2:08x}{iv3:08x}{iv4:08x}{iv5:08x}{iv6:08x}{iv7:08x}{nonce}",
+            iv0 = self.iv[0],
+            iv1 = self.iv[1],
+            iv2 = self.iv[2],
+            iv3 = self.iv[3],
+            iv4 = self.iv[4],
+            iv5 = self.iv[5],
+            iv6 = self.iv[6],
+            iv7 = self.iv[7],
+            nonce = util::nonce_to_raw_string(self.nonce),
+        );
+        write!(f, "{transaction_string}")
+    }
+}
@@ -0,0 +1,714 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::block::Block;
+use crate::ledger::{Ledger, LedgerError, LedgerMode};
+use crate::reward::Schedule;
+use crate::transaction::SignatureError;
+use crate::util::{self, Hashing};
+
+#[derive(Debug)]
+pub enum ChainError {
+    WrongIv,
+    // the block is hashed differently than the chain's blocks
+    WrongHashing(Hashing),
+    Known([u32; 8]),
+    InsufficientWork,
+    MissingCoinbase,
+    Duplicate([u32; 8]),
+    Signature(SignatureError),
+    Ledger(LedgerError),
+    // the persisted ledger isn't what replaying the blocks gives
+    LedgerMismatch,
+}
+
+impl Display for ChainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainError::WrongIv => write!(f, "block doesn't build on a known block"),
+            ChainError::WrongHashing(hashing) => {
+                write!(f, "block is hashed with {hashing}, not the chain's hashing")
+            }
+            ChainError::Known(hash) => {
+                write!(f, "block {} is already known", util::hash_to_hex(hash))
+            }
+            ChainError::InsufficientWork => {
+                write!(f, "block hash doesn't have its k leading zero nibbles")
+            }
+            ChainError::MissingCoinbase => write!(f, "block has no coinbase transaction"),
+            ChainError::Duplicate(txid) => write!(
+                f,
+                "transaction {} is already in the chain",
+                util::hash_to_hex(txid)
+            ),
+            ChainError::Signature(error) => write!(f, "{error}"),
+            ChainError::Ledger(error) => write!(f, "{error}"),
+            ChainError::LedgerMismatch => write!(f, "ledger doesn't match the blocks"),
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+impl From<SignatureError> for ChainError {
+    fn from(error: SignatureError) -> Self {
+        ChainError::Signature(error)
+    }
+}
+
+impl From<LedgerError> for ChainError {
+    fn from(error: LedgerError) -> Self {
+        ChainError::Ledger(error)
+    }
+}
+
+// expected number of hashes it takes to mine a block for `k` nibbles
+pub fn block_work(k: u32) -> u128 {
+    1u128.checked_shl(k.saturating_mul(4)).unwrap_or(u128::MAX)
+}
+
+// where a known block sits in the block tree
+#[derive(Clone, Copy)]
+struct Link {
+    parent: [u32; 8],
+    height: u64,
+    // accumulated work of the branch ending in this block
+    work: u128,
+}
+
+pub enum Accepted {
+    // the block extends the active branch
+    Extended,
+    // the block is on a side branch with no more work than the active one
+    Side,
+    // the block made its branch the active one
+    Reorganized {
+        disconnected: Vec<Block>,
+        connected: Vec<Block>,
+    },
+}
+
+// the mined blocks together with the ledger state they produce. both are persisted in the same file,
+// along with the blocks of side branches that might still overtake the active one.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Chain {
+    // the active branch, the one with the most accumulated work
+    pub(crate) blocks: Vec<Block>,
+    #[serde(default, with = "side_blocks")]
+    side: HashMap<[u32; 8], Block>,
+    pub(crate) ledger: Ledger,
+    pub(crate) schedule: Schedule,
+    // chains from before there was a choice are plain sha256
+    #[serde(default)]
+    pub(crate) hashing: Hashing,
+    #[serde(skip)]
+    pub(crate) txids: HashSet<[u32; 8]>,
+    // hashes of `blocks`
+    #[serde(skip)]
+    hashes: Vec<[u32; 8]>,
+    // every known block, active or not
+    #[serde(skip)]
+    links: HashMap<[u32; 8], Link>,
+}
+
+impl Chain {
+    pub fn new(mode: LedgerMode, schedule: Schedule, hashing: Hashing) -> Self {
+        Self {
+            blocks: vec![],
+            side: HashMap::new(),
+            ledger: Ledger::new(mode),
+            schedule,
+            hashing,
+            txids: HashSet::new(),
+            hashes: vec![],
+            links: HashMap::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        let mut chain: Chain = serde_json::from_slice(&data)?;
+        for block in &chain.blocks {
+            let hash = block.hash();
+            let link = chain.link(block).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "chain blocks aren't linked")
+            })?;
+            chain.links.insert(hash, link);
+            chain.hashes.push(hash);
+            chain.txids.extend(
+                block
+                    .transactions
+                    .iter()
+                    .map(|transaction| transaction.hash()),
+            );
+        }
+        // side blocks are stored in no particular order, so keep linking until nothing changes
+        let mut unlinked: Vec<[u32; 8]> = chain.side.keys().copied().collect();
+        while !unlinked.is_empty() {
+            let before = unlinked.len();
+            unlinked.retain(|hash| match chain.link(&chain.side[hash]) {
+                Some(link) => {
+                    chain.links.insert(*hash, link);
+                    false
+                }
+                None => true,
+            });
+            if unlinked.len() == before {
+                break;
+            }
+        }
+        for hash in unlinked {
+            chain.side.remove(&hash);
+        }
+        Ok(chain)
+    }
+
+    // starts a new chain if there is none at `path` yet. an existing one keeps its own hashing.
+    pub fn open(
+        path: &Path,
+        mode: LedgerMode,
+        schedule: Schedule,
+        hashing: Hashing,
+    ) -> io::Result<Self> {
+        match Self::load(path) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                Ok(Self::new(mode, schedule, hashing))
+            }
+            result => result,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        Self::write(path, &serde_json::to_vec(self)?)
+    }
+
+    // writes a chain already serialized by serde_json to `path`
+    pub(crate) fn write(path: &Path, data: &[u8]) -> io::Result<()> {
+        // write to a temporary file first so a crash doesn't leave a truncated chain behind
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(tmp_path, path)
+    }
+
+    // the active branch, genesis first
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    // how its blocks are hashed
+    pub fn hashing(&self) -> Hashing {
+        self.hashing
+    }
+
+    pub fn tip_hash(&self) -> [u32; 8] {
+        self.hashes.last().copied().unwrap_or_default()
+    }
+
+    // height of the next block
+    pub fn height(&self) -> u64 {
+        self.blocks.len() as u64
+    }
+
+    // accumulated work of the active branch
+    pub fn work(&self) -> u128 {
+        self.links
+            .get(&self.tip_hash())
+            .map(|link| link.work)
+            .unwrap_or_default()
+    }
+
+    // hashes of the active branch from the tip back, dense near the tip and sparse further down, ending in
+    // the genesis block. a peer finds the last block we have in common with it.
+    pub fn locator(&self) -> Vec<[u32; 8]> {
+        let mut locator = vec![];
+        let mut index = self.hashes.len().checked_sub(1);
+        let mut step = 1;
+        while let Some(i) = index {
+            locator.push(self.hashes[i]);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index = i.checked_sub(step);
+        }
+        if let Some(genesis) = self.hashes.first()
+            && locator.last() != Some(genesis)
+        {
+            locator.push(*genesis);
+        }
+        locator
+    }
+
+    // up to `max` active blocks following the first `locator` hash on the active branch
+    pub fn blocks_after(&self, locator: &[[u32; 8]], max: usize) -> Vec<Block> {
+        let start = locator
+            .iter()
+            .find(|hash| self.is_active(hash))
+            .map(|hash| self.links[hash].height as usize + 1)
+            .unwrap_or_default();
+        self.blocks.iter().skip(start).take(max).cloned().collect()
+    }
+
+    // whether the block `hash` is anywhere in the tree
+    pub fn knows(&self, hash: &[u32; 8]) -> bool {
+        self.links.contains_key(hash)
+    }
+
+    pub fn contains(&self, txid: &[u32; 8]) -> bool {
+        self.txids.contains(txid)
+    }
+
+    // where `block` would go in the tree, if its parent is known. the genesis block builds on the zero hash.
+    fn link(&self, block: &Block) -> Option<Link> {
+        let (height, work) = if block.iv == [0; 8] {
+            (0, 0)
+        } else {
+            let parent = self.links.get(&block.iv)?;
+            (parent.height + 1, parent.work)
+        };
+        Some(Link {
+            parent: block.iv,
+            height,
+            work: work.saturating_add(block_work(block.k)),
+        })
+    }
+
+    fn is_active(&self, hash: &[u32; 8]) -> bool {
+        self.links
+            .get(hash)
+            .is_some_and(|link| self.hashes.get(link.height as usize) == Some(hash))
+    }
+
+    // replays the active branch from scratch, which checks everything `load` takes on trust: proof of
+    // work in the chain's hashing, links, signatures and the ledger. fails with the height of the first bad block.
+    pub fn verify(&self) -> Result<(), (u64, ChainError)> {
+        let mut replayed = Chain::new(self.ledger.mode(), self.schedule, self.hashing);
+        for (height, block) in self.blocks.iter().enumerate() {
+            replayed
+                .push(block.clone())
+                .map_err(|error| (height as u64, error))?;
+        }
+        if self.ledger != replayed.ledger {
+            return Err((self.height(), ChainError::LedgerMismatch));
+        }
+        Ok(())
+    }
+
+    // a block either extends the active branch, joins a side branch, or moves the active branch over to
+    // its own one if that ends up with more work. ties go to the branch that was there first.
+    pub fn push(&mut self, block: Block) -> Result<Accepted, ChainError> {
+        if block.hashing != self.hashing {
+            return Err(ChainError::WrongHashing(block.hashing));
+        }
+        let hash = block.hash();
+        if self.links.contains_key(&hash) {
+            return Err(ChainError::Known(hash));
+        }
+        if !block.meets_target() {
+            return Err(ChainError::InsufficientWork);
+        }
+        if block.iv == self.tip_hash() {
+            self.connect(block, hash)?;
+            return Ok(Accepted::Extended);
+        }
+        let Some(link) = self.link(&block) else {
+            return Err(ChainError::WrongIv);
+        };
+        // the ledger can only be checked once the branch becomes active
+        check_transactions(&block)?;
+        self.links.insert(hash, link);
+        self.side.insert(hash, block);
+        if link.work <= self.work() {
+            return Ok(Accepted::Side);
+        }
+        self.reorganize(hash)
+    }
+
+    // all or nothing: the ledger only changes if every transaction of the block is valid
+    fn connect(&mut self, block: Block, hash: [u32; 8]) -> Result<(), ChainError> {
+        if block.iv != self.tip_hash() {
+            return Err(ChainError::WrongIv);
+        }
+        check_transactions(&block)?;
+        let (coinbase, transactions) = block
+            .transactions
+            .split_first()
+            .ok_or(ChainError::MissingCoinbase)?;
+        let mut txids = HashSet::new();
+        for transaction in &block.transactions {
+            let txid = transaction.hash();
+            if self.txids.contains(&txid) || !txids.insert(txid) {
+                return Err(ChainError::Duplicate(txid));
+            }
+        }
+        let fees = transactions
+            .iter()
+            .try_fold(0u64, |acc, transaction| acc.checked_add(transaction.fee))
+            .ok_or(LedgerError::Overflow)?;
+        let allowed = self
+            .schedule
+            .reward(self.height())
+            .checked_add(fees)
+            .ok_or(LedgerError::Overflow)?;
+
+        let mut ledger = self.ledger.clone();
+        ledger.apply_coinbase(coinbase, allowed)?;
+        for transaction in transactions {
+            ledger.apply(transaction)?;
+        }
+        let link = self.link(&block).ok_or(ChainError::WrongIv)?;
+        self.ledger = ledger;
+        self.txids.extend(txids);
+        self.links.insert(hash, link);
+        self.hashes.push(hash);
+        self.blocks.push(block);
+        Ok(())
+    }
+
+    // makes the branch ending in the side block `tip` the active one. the ledger is rolled back by
+    // replaying the blocks up to the fork point and forward by connecting the branch on top. if a branch
+    // block turns out to be invalid, it and everything built on it is forgotten and nothing changes.
+    fn reorganize(&mut self, tip: [u32; 8]) -> Result<Accepted, ChainError> {
+        let mut branch = vec![];
+        let mut cursor = tip;
+        while cursor != [0; 8] && !self.is_active(&cursor) {
+            branch.push(cursor);
+            cursor = self.links[&cursor].parent;
+        }
+        branch.reverse();
+        let fork = if cursor == [0; 8] {
+            0
+        } else {
+            self.links[&cursor].height as usize + 1
+        };
+
+        let mut candidate = Chain::new(self.ledger.mode(), self.schedule, self.hashing);
+        for (block, hash) in self.blocks[..fork].iter().zip(&self.hashes) {
+            candidate.connect(block.clone(), *hash)?;
+        }
+        for hash in &branch {
+            if let Err(error) = candidate.connect(self.side[hash].clone(), *hash) {
+                self.forget(*hash);
+                return Err(error);
+            }
+        }
+
+        let disconnected: Vec<Block> = self.blocks.drain(fork..).collect();
+        for (hash, block) in self.hashes.drain(fork..).zip(&disconnected) {
+            self.side.insert(hash, block.clone());
+        }
+        let connected: Vec<Block> = branch
+            .iter()
+            .filter_map(|hash| self.side.remove(hash))
+            .collect();
+        self.blocks = candidate.blocks;
+        self.hashes = candidate.hashes;
+        self.ledger = candidate.ledger;
+        self.txids = candidate.txids;
+        Ok(Accepted::Reorganized {
+            disconnected,
+            connected,
+        })
+    }
+
+    // drops the side block `hash` and all of its descendants
+    fn forget(&mut self, hash: [u32; 8]) {
+        let mut doomed = vec![hash];
+        while let Some(hash) = doomed.pop() {
+            self.side.remove(&hash);
+            self.links.remove(&hash);
+            doomed.extend(
+                self.side
+                    .iter()
+                    .filter(|(_, block)| block.iv == hash)
+                    .map(|(hash, _)| *hash),
+            );
+        }
+    }
+}
+
+// the checks that don't need the ledger
+fn check_transactions(block: &Block) -> Result<(), ChainError> {
+    if block.transactions.is_empty() {
+        return Err(ChainError::MissingCoinbase);
+    }
+    for transaction in &block.transactions {
+        transaction.verify_signature()?;
+    }
+    Ok(())
+}
+
+// side blocks are keyed by their hash in memory and stored as a plain list
+mod side_blocks {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        side: &HashMap<[u32; 8], Block>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(side.values())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<[u32; 8], Block>, D::Error> {
+        let blocks = Vec::<Block>::deserialize(deserializer)?;
+        Ok(blocks
+            .into_iter()
+            .map(|block| (block.hash(), block))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+    use crate::ledger::{OutPoint, Output};
+    use crate::mempool::{Mempool, Order};
+    use crate::transaction::Transaction;
+    use crate::wallet;
+
+    const SCHEDULE: Schedule = Schedule {
+        initial_reward: 1000,
+        halving_interval: 0,
+    };
+
+    // small k only, no need for the gpu
+    fn mine(block: &Block) -> Block {
+        (0..)
+            .map(|nonce| block.with_nonce(nonce))
+            .find(Block::meets_target)
+            .unwrap()
+    }
+
+    // an empty block on top of `parent` at `height`
+    fn block(parent: [u32; 8], height: u64, k: u32, miner: &SigningKey) -> Block {
+        let coinbase = Transaction::coinbase(height, SCHEDULE.initial_reward, miner);
+        mine(&Block::new(parent, k, vec![coinbase]))
+    }
+
+    #[test]
+    fn three_block_reorg() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let bob = SigningKey::from_bytes(&[2; 32]);
+        let mut chain = Chain::new(LedgerMode::Account, SCHEDULE, Hashing::Sha256);
+        let mut mempool = Mempool::new(Order::Fee, Duration::from_secs(60));
+        let genesis = block([0; 8], 0, 1, &alice);
+        chain.push(genesis.clone()).unwrap();
+
+        // alice mines three blocks, the second one paying carol
+        let mut payment = Transaction {
+            transaction_number: 1,
+            sender: String::new(),
+            datetime: Utc::now(),
+            inputs: vec![],
+            outputs: vec![Output {
+                recipient: "Carol".to_string(),
+                value: 50,
+            }],
+            fee: 2,
+            public_key: None,
+            signature: None,
+        };
+        payment.sign(&alice);
+        for height in 1..4 {
+            if height == 2 {
+                mempool.submit(payment.clone(), &chain).unwrap();
+            }
+            let block = mine(&mempool.template(&chain, 1, 16, &alice));
+            assert!(matches!(chain.push(block.clone()), Ok(Accepted::Extended)));
+            mempool.remove_mined(&block);
+        }
+        assert_eq!(chain.ledger.balance("Carol"), 50);
+        let old_tip = chain.tip_hash();
+
+        // bob mines four blocks on the genesis block. the branch only wins once it has more work.
+        let mut parent = genesis.hash();
+        for height in 1..5 {
+            let block = block(parent, height, 1, &bob);
+            parent = block.hash();
+            match chain.push(block).unwrap() {
+                Accepted::Side => assert!(height < 4),
+                Accepted::Reorganized {
+                    disconnected,
+                    connected,
+                } => {
+                    assert_eq!(height, 4);
+                    assert_eq!(disconnected.len(), 3);
+                    assert_eq!(connected.len(), 4);
+                    mempool.reorganize(&disconnected, &connected, &chain);
+                }
+                Accepted::Extended => panic!("side block extended the chain"),
+            }
+        }
+        assert_eq!(chain.tip_hash(), parent);
+        assert_eq!(chain.height(), 5);
+        assert!(!chain.contains(&payment.hash()));
+        assert_eq!(chain.ledger.balance("Carol"), 0);
+        assert_eq!(
+            chain.ledger.balance(&wallet::address(&bob.verifying_key())),
+            4000
+        );
+        assert_eq!(
+            chain
+                .ledger
+                .balance(&wallet::address(&alice.verifying_key())),
+            1000
+        );
+
+        // the payment is back in the mempool and the old branch is kept around
+        let template = mempool.template(&chain, 1, 16, &bob);
+        assert_eq!(template.transactions.len(), 2);
+        assert_eq!(template.transactions[1].hash(), payment.hash());
+        assert!(matches!(
+            chain.push(block(old_tip, 4, 1, &alice)),
+            Ok(Accepted::Side)
+        ));
+    }
+
+    #[test]
+    fn work_beats_length() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let bob = SigningKey::from_bytes(&[2; 32]);
+        let mut chain = Chain::new(LedgerMode::Utxo, SCHEDULE, Hashing::Sha256);
+        let genesis = block([0; 8], 0, 1, &alice);
+        chain.push(genesis.clone()).unwrap();
+        for height in 1..4 {
+            chain
+                .push(block(chain.tip_hash(), height, 1, &alice))
+                .unwrap();
+        }
+
+        // a single block at k = 2 is worth 16 blocks at k = 1
+        let heavy = block(genesis.hash(), 1, 2, &bob);
+        assert!(matches!(
+            chain.push(heavy.clone()),
+            Ok(Accepted::Reorganized { .. })
+        ));
+        assert_eq!(chain.tip_hash(), heavy.hash());
+        assert_eq!(chain.height(), 2);
+        assert_eq!(chain.work(), block_work(1) + block_work(2));
+        assert_eq!(
+            chain
+                .ledger
+                .balance(&wallet::address(&alice.verifying_key())),
+            1000
+        );
+        assert!(matches!(chain.push(heavy), Err(ChainError::Known(_))));
+    }
+
+    #[test]
+    fn verify_finds_tampering() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let mut chain = Chain::new(LedgerMode::Account, SCHEDULE, Hashing::Sha256);
+        for height in 0..3 {
+            chain
+                .push(block(chain.tip_hash(), height, 1, &alice))
+                .unwrap();
+        }
+        assert!(chain.verify().is_ok());
+
+        let mut forged = chain.clone();
+        forged.ledger = Ledger::new(LedgerMode::Account);
+        assert!(matches!(
+            forged.verify(),
+            Err((3, ChainError::LedgerMismatch))
+        ));
+
+        let mut forged = chain.clone();
+        forged.blocks[1].transactions[0].outputs[0].value += 1;
+        assert!(matches!(forged.verify(), Err((1, _))));
+    }
+
+    #[test]
+    fn verifies_utxo_chains() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let mut chain = Chain::new(LedgerMode::Utxo, SCHEDULE, Hashing::Sha256);
+        let mut mempool = Mempool::new(Order::Fee, Duration::from_secs(60));
+        chain.push(block([0; 8], 0, 1, &alice)).unwrap();
+
+        // every block pays carol out of the coinbase before it, so the set keeps growing
+        for height in 1..8 {
+            let funding = &chain.blocks[height - 1].transactions[0];
+            let input = OutPoint {
+                txid: funding.hash(),
+                index: 0,
+            };
+            let outputs = vec![
+                Output {
+                    recipient: "Carol".to_string(),
+                    value: 10 * height as u64,
+                },
+                Output {
+                    recipient: wallet::address(&alice.verifying_key()),
+                    value: funding.outputs[0].value - 10 * height as u64 - 1,
+                },
+            ];
+            let payment = Transaction::payment(height as u64, vec![input], outputs, 1, &alice);
+            mempool.submit(payment, &chain).unwrap();
+            let block = mine(&mempool.template(&chain, 1, 16, &alice));
+            chain.push(block.clone()).unwrap();
+            mempool.remove_mined(&block);
+        }
+        assert_eq!(chain.ledger.balance("Carol"), 280);
+        assert!(chain.verify().is_ok());
+
+        // the outputs come back from the file in whatever order they were written in
+        let saved: Chain = serde_json::from_slice(&serde_json::to_vec(&chain).unwrap()).unwrap();
+        assert!(saved.verify().is_ok());
+
+        let mut forged = saved.clone();
+        let Ledger::Utxo(utxos) = &mut forged.ledger else {
+            panic!("not a utxo ledger");
+        };
+        utxos.outputs.values_mut().next().unwrap().value += 1;
+        assert!(matches!(
+            forged.verify(),
+            Err((8, ChainError::LedgerMismatch))
+        ));
+    }
+
+    #[test]
+    fn follows_its_hashing() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let mut chain = Chain::new(LedgerMode::Account, SCHEDULE, Hashing::Sha256d);
+        let coinbase = Transaction::coinbase(0, SCHEDULE.initial_reward, &alice);
+        let unmined = Block::new([0; 8], 2, vec![coinbase]);
+        let plain = mine(&unmined);
+        assert!(matches!(
+            chain.push(plain.clone()),
+            Err(ChainError::WrongHashing(Hashing::Sha256))
+        ));
+
+        let double = mine(&unmined.with_hashing(Hashing::Sha256d));
+        let words = double.words();
+        let single = util::hash_with_nonce(&words, double.nonce_offset(), double.nonce());
+        assert_eq!(
+            double.hash(),
+            util::sha256(&util::digest_block(&single)[..])
+        );
+        chain.push(double.clone()).unwrap();
+        assert_eq!(chain.tip_hash(), double.hash());
+        assert!(chain.verify().is_ok());
+
+        // the mode is saved with the chain and its blocks
+        let saved: Chain = serde_json::from_slice(&serde_json::to_vec(&chain).unwrap()).unwrap();
+        assert_eq!(saved.hashing(), Hashing::Sha256d);
+        assert_eq!(saved.blocks[0].hash(), double.hash());
+        let json = serde_json::to_value(&plain).unwrap();
+        assert!(json.get("hashing").is_none());
+    }
+}
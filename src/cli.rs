@@ -0,0 +1,334 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use toy_blockchain::config::Layer;
+use toy_blockchain::ledger::LedgerMode;
+use toy_blockchain::miner;
+use toy_blockchain::util;
+
+pub const DEFAULT_BLOCKS: usize = 3;
+pub const DEFAULT_RPC_ADDR: &str = "127.0.0.1:18443";
+
+/// Toy blockchain, mined on the GPU with a Slang compute shader.
+///
+/// Settings come from ~/.config/toy_blockchain/config.toml, then ./toy_blockchain.toml, then
+/// TOY_BLOCKCHAIN_* environment variables, then the flags, each overriding the ones before. `config`
+/// prints what they resolve to.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file read after the per-user and per-project ones
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Chain file to read and write [config: chain, default chain.json]
+    #[arg(long, global = true, value_name = "FILE")]
+    pub chain: Option<PathBuf>,
+
+    /// Wallet file holding the mining key [config: wallet, default wallet.json]
+    #[arg(long, global = true, value_name = "FILE")]
+    pub wallet: Option<PathBuf>,
+
+    /// How results are printed
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    pub format: Format,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Human readable lines
+    Text,
+    /// A single JSON document
+    Json,
+}
+
+// clap's side of `LedgerMode`, which the library keeps free of clap
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Ledger {
+    /// Balances per address
+    Account,
+    /// Unspent transaction outputs
+    Utxo,
+}
+
+impl From<Ledger> for LedgerMode {
+    fn from(ledger: Ledger) -> Self {
+        match ledger {
+            Ledger::Account => LedgerMode::Account,
+            Ledger::Utxo => LedgerMode::Utxo,
+        }
+    }
+}
+
+// clap's side of `util::Hashing`, which the library keeps free of clap
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Hashing {
+    /// One SHA-256
+    Sha256,
+    /// SHA-256 of the SHA-256 digest, like Bitcoin
+    Sha256d,
+}
+
+impl From<Hashing> for util::Hashing {
+    fn from(hashing: Hashing) -> Self {
+        match hashing {
+            Hashing::Sha256 => util::Hashing::Sha256,
+            Hashing::Sha256d => util::Hashing::Sha256d,
+        }
+    }
+}
+
+// clap's side of `miner::Backend`, which the library keeps free of clap
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// The compute shader, through Vulkan
+    Gpu,
+    /// Every CPU core
+    Cpu,
+}
+
+impl From<Backend> for miner::Backend {
+    fn from(backend: Backend) -> Self {
+        match backend {
+            Backend::Gpu => miner::Backend::Gpu,
+            Backend::Cpu => miner::Backend::Cpu,
+        }
+    }
+}
+
+/// Which miner runs, and where.
+#[derive(Args)]
+pub struct MinerArgs {
+    /// Number of leading zero nibbles a block hash needs [config: k, default 7]
+    #[arg(short, long = "difficulty", value_name = "K")]
+    pub k: Option<u32>,
+
+    /// How block hashes are computed on a new chain, an existing chain keeps its own [config: hashing,
+    /// default sha256]
+    #[arg(long, value_enum)]
+    pub hashing: Option<Hashing>,
+
+    /// How balances are kept on a new chain, an existing chain keeps its own [config: ledger, default
+    /// account]
+    #[arg(long, value_enum)]
+    pub ledger: Option<Ledger>,
+
+    #[command(flatten)]
+    pub backend: BackendArgs,
+}
+
+#[derive(Args)]
+pub struct BackendArgs {
+    /// What does the hashing [config: backend, default gpu, or cpu when built without the gpu feature]
+    #[arg(long, value_enum)]
+    pub backend: Option<Backend>,
+
+    /// GPU to mine on, by index or by part of its name (see `list-devices`) [config: device, default the
+    /// first one]
+    #[arg(long)]
+    pub device: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start a new chain and mine blocks paying the coursework recipients into it, refusing to overwrite an
+    /// existing chain file
+    Mine {
+        #[command(flatten)]
+        miner: MinerArgs,
+
+        /// Number of blocks to mine, the genesis block included
+        #[arg(short = 'n', long, default_value_t = DEFAULT_BLOCKS)]
+        blocks: usize,
+    },
+    /// Replay the chain file from scratch, checking proof of work, links, signatures and the ledger
+    Verify,
+    /// Measure the hashrate of a backend
+    Bench {
+        #[command(flatten)]
+        miner: MinerArgs,
+
+        /// How long to hash for
+        #[arg(long, default_value_t = 10)]
+        seconds: u64,
+    },
+    /// List the GPUs that can mine
+    ListDevices,
+    /// Print the blocks of the chain file
+    ShowChain,
+    /// SHA-256 of a string, computed the way blocks are hashed
+    Hash {
+        /// What to hash. Standard input if left out
+        input: Option<String>,
+
+        /// sha256d hashes the digest again [config: hashing, default sha256]
+        #[arg(long, value_enum)]
+        hashing: Option<Hashing>,
+    },
+    /// Manage the encrypted wallet
+    Wallet {
+        #[command(subcommand)]
+        command: WalletCommand,
+    },
+    /// Run a node that syncs with its peers and mines on the best chain until stopped over RPC
+    Node {
+        /// Address to accept peers on
+        listen: String,
+
+        /// Peers to connect to
+        peers: Vec<String>,
+
+        #[command(flatten)]
+        miner: MinerArgs,
+
+        /// Address of the JSON-RPC server
+        #[arg(long, default_value = DEFAULT_RPC_ADDR)]
+        rpc: String,
+    },
+    /// Run or inspect a mining pool
+    Pool {
+        #[command(subcommand)]
+        command: PoolCommand,
+    },
+    /// Mine the jobs of a pool
+    PoolClient {
+        /// Address of the pool
+        pool: String,
+
+        /// Worker name, which is also the address shares are paid to
+        worker: String,
+
+        #[command(flatten)]
+        backend: BackendArgs,
+    },
+    /// Print the resolved configuration
+    Config,
+}
+
+#[derive(Subcommand)]
+pub enum WalletCommand {
+    /// Create a wallet with a new key
+    Create,
+    /// Check the passphrase by decrypting every key
+    Unlock,
+    /// Add a new key
+    AddKey {
+        /// Name of the key
+        #[arg(default_value = toy_blockchain::wallet::DEFAULT_KEY)]
+        name: String,
+    },
+    /// List the keys with their addresses
+    List,
+    /// Print a key, secret included
+    Export {
+        /// Name of the key
+        #[arg(default_value = toy_blockchain::wallet::DEFAULT_KEY)]
+        name: String,
+    },
+    /// Re-encrypt the wallet under a new passphrase
+    ChangePassphrase,
+}
+
+#[derive(Subcommand)]
+pub enum PoolCommand {
+    /// Run a node that hands out jobs to pool clients instead of mining itself
+    Serve {
+        /// Address to accept peers on
+        listen: String,
+
+        /// Address to accept pool clients on
+        pool_listen: String,
+
+        /// Peers to connect to
+        peers: Vec<String>,
+
+        /// Number of leading zero nibbles a block hash needs [config: k, default 7]
+        #[arg(short, long = "difficulty", value_name = "K")]
+        k: Option<u32>,
+    },
+    /// Show what the share log says about every worker
+    Stats {
+        /// Number of leading zero nibbles a block hash needs [config: k, default 7]
+        #[arg(short, long = "difficulty", value_name = "K")]
+        k: Option<u32>,
+    },
+}
+
+impl Cli {
+    // what the flags say about the config, which overrides everything else
+    pub fn layer(&self) -> Layer {
+        let mut layer = Layer {
+            chain: self.chain.clone(),
+            wallet: self.wallet.clone(),
+            ..Default::default()
+        };
+        let (k, hashing, backend) = match &self.command {
+            Command::Mine { miner, .. }
+            | Command::Bench { miner, .. }
+            | Command::Node { miner, .. } => {
+                layer.ledger = miner.ledger.map(LedgerMode::from);
+                (miner.k, miner.hashing, Some(&miner.backend))
+            }
+            Command::Pool {
+                command: PoolCommand::Serve { k, .. } | PoolCommand::Stats { k },
+            } => (*k, None, None),
+            Command::PoolClient { backend, .. } => (None, None, Some(backend)),
+            Command::Hash { hashing, .. } => (None, *hashing, None),
+            _ => (None, None, None),
+        };
+        layer.k = k;
+        layer.hashing = hashing.map(util::Hashing::from);
+        if let Some(backend) = backend {
+            layer.backend = backend.backend.map(miner::Backend::from);
+            layer.device = backend.device.clone();
+        }
+        layer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn parses() {
+        Cli::command().debug_assert();
+        let cli = Cli::try_parse_from([
+            "toy_blockchain",
+            "mine",
+            "-k",
+            "5",
+            "--backend",
+            "cpu",
+            "--hashing",
+            "sha256d",
+            "--ledger",
+            "utxo",
+            "--blocks",
+            "4",
+            "--format",
+            "json",
+        ])
+        .unwrap();
+        assert_eq!(cli.format, Format::Json);
+        let layer = cli.layer();
+        assert_eq!(
+            (layer.k, layer.backend),
+            (Some(5), Some(miner::Backend::Cpu))
+        );
+        assert_eq!(layer.hashing, Some(util::Hashing::Sha256d));
+        assert_eq!(layer.ledger, Some(LedgerMode::Utxo));
+        assert!(layer.chain.is_none());
+        let Command::Mine { blocks, .. } = cli.command else {
+            panic!("not mine");
+        };
+        assert_eq!(blocks, 4);
+        assert!(Cli::try_parse_from(["toy_blockchain", "mine", "--backend", "tpu"]).is_err());
+    }
+}
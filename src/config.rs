@@ -0,0 +1,361 @@
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ledger::LedgerMode;
+use crate::miner::{AnyMiner, Backend, BackendError, MIN_WORK_GROUPS, WORK_GROUP_COUNTS};
+use crate::transaction::{self, Coursework};
+use crate::util::Hashing;
+
+// read after the per-user file, so a project can override the user's settings
+pub const PROJECT_CONFIG_PATH: &str = "toy_blockchain.toml";
+const USER_CONFIG_PATH: &str = "toy_blockchain/config.toml";
+const ENV_PREFIX: &str = "TOY_BLOCKCHAIN_";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Env(String, String),
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, error) => write!(f, "{}: {error}", path.display()),
+            ConfigError::Parse(path, error) => write!(f, "{}: {error}", path.display()),
+            ConfigError::Env(var, error) => write!(f, "{var}: {error}"),
+            ConfigError::Invalid(error) => write!(f, "invalid config: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// what used to be constants. resolved from the defaults, the per-user config file, the per-project one,
+// the environment and the command line, each overriding the ones before.
+#[derive(Clone, Debug, Serialize)]
+pub struct Config {
+    // number of the genesis transaction, the later ones count up from it
+    pub matrikel_nummer: u64,
+    // paid in turn by the transactions of `mine`
+    pub recipients: Vec<String>,
+    // what each recipient is paid
+    pub values: Vec<u64>,
+    pub k: u32,
+    // account balances or unspent outputs, of new chains like `hashing`
+    pub ledger: LedgerMode,
+    // of new chains, an existing chain keeps its own
+    pub hashing: Hashing,
+    // has to be WORK_GROUP_COUNTS, the one dispatch that covers a generation of the shader exactly
+    pub work_group_counts: [u32; 3],
+    pub backend: Backend,
+    // by index or by part of the name, the first device if unset
+    pub device: Option<String>,
+    pub chain: PathBuf,
+    pub wallet: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            matrikel_nummer: transaction::MATRIKEL_NUMMER,
+            recipients: transaction::RECIPIENTS.map(String::from).to_vec(),
+            values: transaction::VALUES.to_vec(),
+            k: 7,
+            ledger: LedgerMode::Account,
+            hashing: Hashing::Sha256,
+            work_group_counts: WORK_GROUP_COUNTS,
+            backend: if cfg!(feature = "gpu") {
+                Backend::Gpu
+            } else {
+                Backend::Cpu
+            },
+            device: None,
+            chain: "chain.json".into(),
+            wallet: "wallet.json".into(),
+        }
+    }
+}
+
+// one source of settings. what it leaves out stays as the sources before it set it.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layer {
+    pub matrikel_nummer: Option<u64>,
+    pub recipients: Option<Vec<String>>,
+    pub values: Option<Vec<u64>>,
+    pub k: Option<u32>,
+    pub ledger: Option<LedgerMode>,
+    pub hashing: Option<Hashing>,
+    pub work_group_counts: Option<[u32; 3]>,
+    pub backend: Option<Backend>,
+    pub device: Option<String>,
+    pub chain: Option<PathBuf>,
+    pub wallet: Option<PathBuf>,
+}
+
+impl Layer {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let data = fs::read_to_string(path).map_err(|error| ConfigError::Io(path.into(), error))?;
+        toml::from_str(&data).map_err(|error| ConfigError::Parse(path.into(), error))
+    }
+
+    // `TOY_BLOCKCHAIN_K`, `TOY_BLOCKCHAIN_RECIPIENTS` and so on. lists are comma separated.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let var = &var;
+        Ok(Layer {
+            matrikel_nummer: env_var(var, "matrikel_nummer", parse_value)?,
+            recipients: env_var(var, "recipients", parse_list)?,
+            values: env_var(var, "values", parse_list)?,
+            k: env_var(var, "k", parse_value)?,
+            ledger: env_var(var, "ledger", parse_value)?,
+            hashing: env_var(var, "hashing", parse_value)?,
+            work_group_counts: env_var(var, "work_group_counts", |value| {
+                parse_list(value)?
+                    .try_into()
+                    .map_err(|_| "expected three counts".to_string())
+            })?,
+            backend: env_var(var, "backend", parse_value)?,
+            device: env_var(var, "device", parse_value)?,
+            chain: env_var(var, "chain", parse_value)?,
+            wallet: env_var(var, "wallet", parse_value)?,
+        })
+    }
+}
+
+// the variable for `field`, run through `parse` if it's set
+fn env_var<T>(
+    var: &impl Fn(&str) -> Option<String>,
+    field: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Option<T>, ConfigError> {
+    let name = format!("{ENV_PREFIX}{}", field.to_uppercase());
+    var(&name)
+        .map(|value| parse(&value).map_err(|error| ConfigError::Env(name, error)))
+        .transpose()
+}
+
+fn parse_value<T: FromStr<Err: Display>>(value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|error: T::Err| error.to_string())
+}
+
+fn parse_list<T: FromStr<Err: Display>>(value: &str) -> Result<Vec<T>, String> {
+    value.split(',').map(parse_value).collect()
+}
+
+// $XDG_CONFIG_HOME, or ~/.config without it
+fn user_config_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(dir.join(USER_CONFIG_PATH))
+}
+
+impl Config {
+    // missing config files are skipped, except for `extra`, which the user asked for by name
+    pub fn resolve(flags: Layer, extra: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut config = Config::default();
+        for path in user_config_path()
+            .into_iter()
+            .chain([PathBuf::from(PROJECT_CONFIG_PATH)])
+        {
+            match Layer::load(&path) {
+                Err(ConfigError::Io(_, error)) if error.kind() == io::ErrorKind::NotFound => {}
+                layer => config.apply(layer?),
+            }
+        }
+        if let Some(path) = extra {
+            config.apply(Layer::load(path)?);
+        }
+        config.apply(Layer::from_env()?);
+        config.apply(flags);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn apply(&mut self, layer: Layer) {
+        let Layer {
+            matrikel_nummer,
+            recipients,
+            values,
+            k,
+            ledger,
+            hashing,
+            work_group_counts,
+            backend,
+            device,
+            chain,
+            wallet,
+        } = layer;
+        self.matrikel_nummer = matrikel_nummer.unwrap_or(self.matrikel_nummer);
+        self.recipients = recipients.unwrap_or(std::mem::take(&mut self.recipients));
+        self.values = values.unwrap_or(std::mem::take(&mut self.values));
+        self.k = k.unwrap_or(self.k);
+        self.ledger = ledger.unwrap_or(self.ledger);
+        self.hashing = hashing.unwrap_or(self.hashing);
+        self.work_group_counts = work_group_counts.unwrap_or(self.work_group_counts);
+        self.backend = backend.unwrap_or(self.backend);
+        self.device = device.or(self.device.take());
+        self.chain = chain.unwrap_or(std::mem::take(&mut self.chain));
+        self.wallet = wallet.unwrap_or(std::mem::take(&mut self.wallet));
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.recipients.is_empty() {
+            return Err(ConfigError::Invalid("no recipients".to_string()));
+        }
+        if self.values.len() != self.recipients.len() {
+            return Err(ConfigError::Invalid(format!(
+                "{} values for {} recipients",
+                self.values.len(),
+                self.recipients.len()
+            )));
+        }
+        // a hash has 64 nibbles
+        if !(1..=64).contains(&self.k) {
+            return Err(ConfigError::Invalid(format!(
+                "k = {} isn't in 1..=64",
+                self.k
+            )));
+        }
+        // the shader moves the nonce by a fixed stride per generation, which only one dispatch size covers
+        let [x, y, z] = self.work_group_counts;
+        if x < MIN_WORK_GROUPS {
+            return Err(ConfigError::Invalid(format!(
+                "{x} work groups skip nonces, {MIN_WORK_GROUPS} are needed"
+            )));
+        }
+        if self.work_group_counts != WORK_GROUP_COUNTS {
+            return Err(ConfigError::Invalid(format!(
+                "work group counts [{x}, {y}, {z}] repeat nonces, they have to be {WORK_GROUP_COUNTS:?}"
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn coursework(&self) -> Coursework {
+        Coursework {
+            matrikel_nummer: self.matrikel_nummer,
+            recipients: self.recipients.clone(),
+            values: self.values.clone(),
+        }
+    }
+
+    // the miner of the configured backend, starting on `words`
+    pub fn miner(&self, words: &[u32]) -> Result<AnyMiner, BackendError> {
+        AnyMiner::new(
+            self.backend,
+            self.device.as_deref(),
+            self.work_group_counts,
+            words,
+        )
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("the config always serializes")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn layers_override_in_order() {
+        let mut config = Config::default();
+        config.apply(
+            toml::from_str(
+                r#"
+                k = 5
+                backend = "cpu"
+                hashing = "sha256"
+                ledger = "utxo"
+                work_group_counts = [8192, 1, 1]
+                recipients = ["Dave"]
+                values = [1]
+                "#,
+            )
+            .unwrap(),
+        );
+        let env = HashMap::from([
+            ("TOY_BLOCKCHAIN_K", "6"),
+            ("TOY_BLOCKCHAIN_WORK_GROUP_COUNTS", "4096, 1, 1"),
+            ("TOY_BLOCKCHAIN_HASHING", "SHA256D"),
+        ]);
+        config
+            .apply(Layer::from_vars(|name| env.get(name).map(|value| value.to_string())).unwrap());
+        config.apply(Layer {
+            k: Some(4),
+            ..Default::default()
+        });
+        assert_eq!(config.k, 4);
+        assert_eq!(config.backend, Backend::Cpu);
+        assert_eq!(config.hashing, Hashing::Sha256d);
+        assert_eq!(config.ledger, LedgerMode::Utxo);
+        assert_eq!(config.work_group_counts, [4096, 1, 1]);
+        assert_eq!(config.recipients, ["Dave"]);
+        assert_eq!(config.chain, Path::new("chain.json"));
+        assert!(config.validate().is_ok());
+        assert!(config.to_toml().contains("k = 4"));
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(toml::from_str::<Layer>("difficulty = 5").is_err());
+        for (name, value) in [
+            ("TOY_BLOCKCHAIN_BACKEND", "tpu"),
+            ("TOY_BLOCKCHAIN_LEDGER", "bank"),
+            ("TOY_BLOCKCHAIN_HASHING", "sha512"),
+        ] {
+            let env = HashMap::from([(name, value)]);
+            assert!(matches!(
+                Layer::from_vars(|name| env.get(name).map(|value| value.to_string())),
+                Err(ConfigError::Env(..))
+            ));
+        }
+        let mut config = Config::default();
+        config.apply(Layer {
+            values: Some(vec![1]),
+            ..Default::default()
+        });
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        for counts in [
+            [0, 1, 1],
+            [MIN_WORK_GROUPS - 1, 1, 1],
+            [MIN_WORK_GROUPS + 1, 1, 1],
+            [MIN_WORK_GROUPS * 2, 1, 1],
+            [MIN_WORK_GROUPS, 2, 1],
+            [MIN_WORK_GROUPS, 1, 0],
+        ] {
+            let config = Config {
+                work_group_counts: counts,
+                ..Default::default()
+            };
+            assert!(
+                matches!(config.validate(), Err(ConfigError::Invalid(_))),
+                "{counts:?}"
+            );
+        }
+        let config = Config {
+            work_group_counts: [MIN_WORK_GROUPS, 1, 1],
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+    }
+}
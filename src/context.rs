@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use vulkano::NonNullDeviceAddress;
@@ -22,14 +23,50 @@ use vulkano::pipeline::PipelineLayout;
 use vulkano::pipeline::PipelineShaderStageCreateInfo;
 use vulkano::pipeline::compute::ComputePipelineCreateInfo;
 use vulkano::pipeline::layout::PushConstantRange;
-use vulkano::shader::ShaderStages;
+use vulkano::shader::{EntryPoint, ShaderModule, ShaderStages, SpecializationConstant};
 use vulkano::sync;
 use vulkano::sync::GpuFuture;
 
+use crate::miner::{BackendError, Miner};
+use crate::pipeline_cache::DiskCache;
 use crate::push_constants::PushConstants;
 use crate::shader;
+use crate::util::Hashing;
 
-const WORK_GROUP_COUNTS: [u32; 3] = [8192, 1, 1];
+// by index first, then by a case insensitive part of the name
+fn select_device(
+    devices: Vec<Arc<PhysicalDevice>>,
+    device: Option<&str>,
+) -> Option<Arc<PhysicalDevice>> {
+    let Some(device) = device else {
+        return devices.into_iter().next();
+    };
+    if let Ok(index) = device.parse::<usize>() {
+        return devices.into_iter().nth(index);
+    }
+    let device = device.to_lowercase();
+    devices.into_iter().find(|physical_device| {
+        physical_device
+            .properties()
+            .device_name
+            .to_lowercase()
+            .contains(&device)
+    })
+}
+
+// a specialized pipeline is built for every distinct one of these
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct JobShape {
+    pub(crate) block_count: u32,
+    pub(crate) nonce_index: u32,
+    pub(crate) k: u32,
+    pub(crate) hashing: Hashing,
+    pub(crate) early_exit: bool,
+}
+
+// the nonce index moves with the length of the transactions, so shapes keep coming. building a pipeline
+// takes a while, but not so long that forgetting them all now and then hurts.
+const MAX_VARIANTS: usize = 16;
 
 pub struct Context {
     pub(crate) _library: Arc<VulkanLibrary>,
@@ -37,16 +74,86 @@ pub struct Context {
     pub(crate) _physical_device: Arc<PhysicalDevice>,
     pub(crate) device: Arc<Device>,
     pub(crate) queue: Arc<Queue>,
-    pub(crate) _allocator: Arc<StandardMemoryAllocator>,
+    pub(crate) allocator: Arc<StandardMemoryAllocator>,
     pub(crate) words_buffer: Arc<Subbuffer<[u32]>>,
     pub(crate) nonce_buffer: Arc<Subbuffer<u64>>,
     pub(crate) pipeline_layout: Arc<PipelineLayout>,
-    pub(crate) pipeline: Arc<ComputePipeline>,
+    // so the driver doesn't compile the unrolled sha256 from scratch every run
+    pub(crate) pipeline_cache: DiskCache,
     pub(crate) command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    pub(crate) work_group_counts: [u32; 3],
+    // what every search runs, specialized for the job's shape
+    specialized: Arc<ShaderModule>,
+    // whether the shader stops checking a digest at its first word that misses k, see `set_early_exit`
+    pub(crate) early_exit: bool,
+    variants: HashMap<JobShape, Arc<ComputePipeline>>,
 }
 
 impl Context {
-    pub fn new(words: &Vec<u32>) -> Self {
+    // runs on the device `device` names, by its index in `devices` or by part of its name. the first
+    // one if it's `None`.
+    pub fn on_device(
+        words: &[u32],
+        device: Option<&str>,
+        work_group_counts: [u32; 3],
+    ) -> Result<Self, BackendError> {
+        let (library, instance) = Self::instance();
+        let physical_device = select_device(Self::enumerate(&instance), device)
+            .ok_or_else(|| BackendError::UnknownDevice(device.unwrap_or_default().to_string()))?;
+        let mut context = Self::with_physical_device(
+            library,
+            instance,
+            physical_device,
+            words,
+            work_group_counts,
+        );
+        // better now than as a block the cpu rejects after hours of mining
+        context.self_test()?;
+        Ok(context)
+    }
+
+    // a small context for tests, on the device `TOY_BLOCKCHAIN_TEST_DEVICE` names or the first one.
+    // `None` where there is no vulkan at all, unless the variable is set: then the device is required, so a
+    // runner with lavapipe (`TOY_BLOCKCHAIN_TEST_DEVICE=llvmpipe`) can't skip the gpu tests by accident.
+    #[cfg(test)]
+    pub(crate) fn for_tests(words: &[u32]) -> Option<Self> {
+        let device = std::env::var("TOY_BLOCKCHAIN_TEST_DEVICE").ok();
+        let physical_device = || {
+            let library = VulkanLibrary::new().ok()?;
+            let instance = Instance::new(
+                library.clone(),
+                InstanceCreateInfo {
+                    flags: InstanceCreateFlags::ENUMERATE_PORTABILITY,
+                    ..Default::default()
+                },
+            )
+            .ok()?;
+            let devices = instance.enumerate_physical_devices().ok()?.collect();
+            let physical_device = select_device(devices, device.as_deref())?;
+            Some((library, instance, physical_device))
+        };
+        let Some((library, instance, physical_device)) = physical_device() else {
+            if let Some(device) = &device {
+                panic!(
+                    "TOY_BLOCKCHAIN_TEST_DEVICE is set, but no vulkan device matches `{device}`"
+                );
+            }
+            return None;
+        };
+        // 8192 nonces out of every generation, which a software renderer hashes quickly. the ones skipped
+        // in between don't matter here, though config rejects counts this small.
+        let mut context =
+            Self::with_physical_device(library, instance, physical_device, words, [64, 1, 1]);
+        context.self_test().unwrap();
+        Some(context)
+    }
+
+    // the devices a context can run on, in the order `on_device` counts them
+    pub fn devices() -> Vec<Arc<PhysicalDevice>> {
+        Self::enumerate(&Self::instance().1)
+    }
+
+    fn instance() -> (Arc<VulkanLibrary>, Arc<Instance>) {
         let library = VulkanLibrary::new().expect("no local Vulkan library/DLL");
         let instance = Instance::new(
             library.clone(),
@@ -56,13 +163,23 @@ impl Context {
             },
         )
         .expect("failed to create instance");
+        (library, instance)
+    }
 
-        let physical_device = instance
+    fn enumerate(instance: &Arc<Instance>) -> Vec<Arc<PhysicalDevice>> {
+        instance
             .enumerate_physical_devices()
             .expect("could not enumerate devices")
-            .next()
-            .expect("no devices available");
+            .collect()
+    }
 
+    fn with_physical_device(
+        library: Arc<VulkanLibrary>,
+        instance: Arc<Instance>,
+        physical_device: Arc<PhysicalDevice>,
+        words: &[u32],
+        work_group_counts: [u32; 3],
+    ) -> Self {
         let queue_family_index = physical_device
             .queue_family_properties()
             .iter()
@@ -95,20 +212,7 @@ impl Context {
         let queue = queues.next().unwrap();
         let memory_allocator = Arc::new(StandardMemoryAllocator::new_default(device.clone()));
 
-        let words_buffer = Buffer::from_iter(
-            memory_allocator.clone(),
-            vulkano::buffer::BufferCreateInfo {
-                usage: BufferUsage::UNIFORM_BUFFER | BufferUsage::SHADER_DEVICE_ADDRESS,
-                ..Default::default()
-            },
-            vulkano::memory::allocator::AllocationCreateInfo {
-                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
-                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
-                ..Default::default()
-            },
-            words.iter().map(|x| *x),
-        )
-        .expect("Failed to create the words buffer!");
+        let words_buffer = Self::create_words_buffer(memory_allocator.clone(), words);
 
         let nonce = 0u64;
         let nonce_buffer = Buffer::from_data(
@@ -126,25 +230,19 @@ impl Context {
         )
         .expect("Failed to create the nonce buffer!");
 
-        let shader = shader::load(device.clone()).unwrap();
-        let cs = shader.entry_point("main").unwrap();
-        let stage = PipelineShaderStageCreateInfo::new(cs);
-
-        let mut pipeline_layout_create_info =
-            vulkano::pipeline::layout::PipelineLayoutCreateInfo::default();
-        pipeline_layout_create_info.push_constant_ranges = vec![PushConstantRange {
-            stages: ShaderStages::COMPUTE,
-            offset: 0,
-            size: core::mem::size_of::<PushConstants>() as u32,
-        }];
+        let pipeline_layout_create_info = vulkano::pipeline::layout::PipelineLayoutCreateInfo {
+            push_constant_ranges: vec![PushConstantRange {
+                stages: ShaderStages::COMPUTE,
+                offset: 0,
+                size: core::mem::size_of::<PushConstants>() as u32,
+            }],
+            ..Default::default()
+        };
         let layout = PipelineLayout::new(device.clone(), pipeline_layout_create_info).unwrap();
 
-        let pipeline = ComputePipeline::new(
-            device.clone(),
-            None,
-            ComputePipelineCreateInfo::stage_layout(stage, layout.clone()),
-        )
-        .expect("Failed to create pipeline");
+        let pipeline_cache = DiskCache::open(&device);
+        let specialized = shader::load_specialized(device.clone())
+            .expect("failed to load the specialized shader");
 
         let command_buffer_allocator = Arc::new(StandardCommandBufferAllocator::new(
             device.clone(),
@@ -157,24 +255,95 @@ impl Context {
             _physical_device: physical_device.clone(),
             device: device.clone(),
             queue: queue.clone(),
-            _allocator: memory_allocator.clone(),
+            allocator: memory_allocator.clone(),
             words_buffer: Arc::new(words_buffer.clone()),
             nonce_buffer: Arc::new(nonce_buffer.clone()),
             pipeline_layout: layout.clone(),
-            pipeline: pipeline.clone(),
+            pipeline_cache,
             command_buffer_allocator: command_buffer_allocator.clone(),
+            work_group_counts,
+            specialized,
+            early_exit: true,
+            variants: HashMap::new(),
         }
     }
 
-    pub fn words_address(&self) -> NonNullDeviceAddress {
+    // on by default. off, the shader adds up the whole digest before checking it, which the gpu benches
+    // compare the early exit with.
+    pub fn set_early_exit(&mut self, early_exit: bool) {
+        self.early_exit = early_exit;
+    }
+
+    pub(crate) fn words_address(&self) -> NonNullDeviceAddress {
         self.words_buffer.device_address().unwrap()
     }
 
-    pub fn nonce_address(&self) -> NonNullDeviceAddress {
+    pub(crate) fn nonce_address(&self) -> NonNullDeviceAddress {
         self.nonce_buffer.device_address().unwrap()
     }
 
-    pub async fn invoke(&mut self, push_constants: &PushConstants) -> u64 {
+    pub(crate) fn create_pipeline(
+        device: &Arc<Device>,
+        layout: &Arc<PipelineLayout>,
+        pipeline_cache: &DiskCache,
+        entry_point: EntryPoint,
+    ) -> Arc<ComputePipeline> {
+        let stage = PipelineShaderStageCreateInfo::new(entry_point);
+        let pipeline = ComputePipeline::new(
+            device.clone(),
+            Some(pipeline_cache.cache.clone()),
+            ComputePipelineCreateInfo::stage_layout(stage, layout.clone()),
+        )
+        .expect("Failed to create pipeline");
+        // the next run can still mine without it, just with a slower start
+        if let Err(error) = pipeline_cache.save() {
+            eprintln!("failed to save the pipeline cache: {error}");
+        }
+        pipeline
+    }
+
+    // the pipeline specialized for `shape`, built the first time the shape comes up
+    pub(crate) fn pipeline_for(&mut self, shape: JobShape) -> Arc<ComputePipeline> {
+        if let Some(pipeline) = self.variants.get(&shape) {
+            return pipeline.clone();
+        }
+        // vulkano hashes its maps with foldhash, hence the collect
+        let constants = [
+            (0, SpecializationConstant::U32(shape.block_count)),
+            (1, SpecializationConstant::U32(shape.nonce_index)),
+            (2, SpecializationConstant::U32(shape.k)),
+            (
+                3,
+                SpecializationConstant::Bool(shape.hashing == Hashing::Sha256d),
+            ),
+            (4, SpecializationConstant::Bool(shape.early_exit)),
+        ]
+        .into_iter()
+        .collect();
+        let entry_point = self
+            .specialized
+            .specialize(constants)
+            .expect("the specialization constants don't match the shader")
+            .entry_point("main")
+            .unwrap();
+        let pipeline = Self::create_pipeline(
+            &self.device,
+            &self.pipeline_layout,
+            &self.pipeline_cache,
+            entry_point,
+        );
+        if self.variants.len() >= MAX_VARIANTS {
+            self.variants.clear();
+        }
+        self.variants.insert(shape, pipeline.clone());
+        pipeline
+    }
+
+    pub(crate) fn invoke(
+        &mut self,
+        pipeline: Arc<ComputePipeline>,
+        push_constants: &PushConstants,
+    ) -> u64 {
         let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
             self.command_buffer_allocator.clone(),
             self.queue.queue_family_index(),
@@ -189,11 +358,11 @@ impl Context {
 
         unsafe {
             command_buffer_builder
-                .bind_pipeline_compute(self.pipeline.clone())
+                .bind_pipeline_compute(pipeline)
                 .unwrap()
                 .push_constants(self.pipeline_layout.clone(), 0, *push_constants)
                 .unwrap()
-                .dispatch(WORK_GROUP_COUNTS)
+                .dispatch(self.work_group_counts)
                 .unwrap();
         }
         let command_buffer = command_buffer_builder.build().unwrap().clone();
@@ -205,13 +374,234 @@ impl Context {
             .unwrap();
         future.wait(None).unwrap();
 
-        let nonce = *self.nonce_buffer.read().unwrap();
-
-        nonce
+        *self.nonce_buffer.read().unwrap()
     }
 
-    pub fn update_words(&mut self, words: &Vec<u32>) {
+    // NOTE: transactions don't all pad to the same length, so the buffer (and with it the words address) may change here
+    pub(crate) fn update_words(&mut self, words: &[u32]) {
+        if self.words_buffer.len() != words.len() as u64 {
+            self.words_buffer = Arc::new(Self::create_words_buffer(self.allocator.clone(), words));
+            return;
+        }
         let mut write_words = self.words_buffer.write().unwrap();
-        write_words.copy_from_slice(words.as_slice());
+        write_words.copy_from_slice(words);
+    }
+
+    pub(crate) fn create_words_buffer(
+        allocator: Arc<StandardMemoryAllocator>,
+        words: &[u32],
+    ) -> Subbuffer<[u32]> {
+        Buffer::from_iter(
+            allocator,
+            vulkano::buffer::BufferCreateInfo {
+                usage: BufferUsage::UNIFORM_BUFFER | BufferUsage::SHADER_DEVICE_ADDRESS,
+                ..Default::default()
+            },
+            vulkano::memory::allocator::AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            words.iter().copied(),
+        )
+        .expect("Failed to create the words buffer!")
+    }
+}
+
+impl Miner for Context {
+    async fn search(
+        &mut self,
+        words: &[u32],
+        nonce_index: u32,
+        k: u32,
+        hashing: Hashing,
+        generation: u64,
+    ) -> u64 {
+        self.update_words(words);
+        let push_constants = PushConstants {
+            generation,
+            word_count: words.len() as u32,
+            nonce_index,
+            words: self.words_address().into(),
+            nonce: self.nonce_address().into(),
+            k,
+        };
+        let pipeline = self.pipeline_for(JobShape {
+            block_count: words.len() as u32 / 16,
+            nonce_index,
+            k,
+            hashing,
+            early_exit: self.early_exit,
+        });
+        self.invoke(pipeline, &push_constants)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+
+    // a block the way the miners see it: padded words with room for the nonce at `nonce_index`
+    fn block(text: &str) -> (Vec<u32>, u32) {
+        let mut data = text.as_bytes().to_vec();
+        data.resize(data.len().next_multiple_of(4), b' ');
+        let nonce_index = data.len() as u32 / 4;
+        data.extend([0; 8]);
+        (util::to_words(&util::pad(&data)), nonce_index)
+    }
+
+    // the first nonce the sha256 pipeline for the shape finds, checked on the cpu
+    fn mine(context: &mut Context, words: &[u32], nonce_index: u32, k: u32) -> u64 {
+        let pipeline = context.pipeline_for(JobShape {
+            block_count: words.len() as u32 / 16,
+            nonce_index,
+            k,
+            hashing: Hashing::Sha256,
+            early_exit: context.early_exit,
+        });
+        for generation in 0..16 {
+            let push_constants = PushConstants {
+                generation,
+                word_count: words.len() as u32,
+                nonce_index,
+                words: context.words_address().into(),
+                nonce: context.nonce_address().into(),
+                k,
+            };
+            let nonce = context.invoke(pipeline.clone(), &push_constants);
+            if nonce == 0 {
+                continue;
+            }
+            let start = generation * crate::miner::GENERATION_STRIDE;
+            assert!((start..start + 64 * 128).contains(&nonce), "nonce {nonce}");
+            let hash = util::hash_with_nonce(words, nonce_index, nonce);
+            let bytes: Vec<u8> = hash.iter().flat_map(|word| word.to_be_bytes()).collect();
+            assert!(
+                util::check_k_nibbles(&bytes, k),
+                "nonce {nonce} hashes to {}",
+                util::hash_to_hex(&hash)
+            );
+            return nonce;
+        }
+        panic!("no nonce at k = {k} in 16 generations");
+    }
+
+    // these skip without a vulkan device, set TOY_BLOCKCHAIN_TEST_DEVICE to require one
+
+    #[test]
+    fn mines_at_low_k() {
+        let (words, nonce_index) = block("Alice pays Bob 5");
+        let Some(mut context) = Context::for_tests(&words) else {
+            return;
+        };
+        for k in [2, 3] {
+            mine(&mut context, &words, nonce_index, k);
+        }
+    }
+
+    #[test]
+    fn honors_update_words() {
+        let (first, first_index) = block("Alice pays Bob 5");
+        let Some(mut context) = Context::for_tests(&first) else {
+            return;
+        };
+        mine(&mut context, &first, first_index, 3);
+
+        // same length, written into the buffer in place
+        let (second, second_index) = block("Alice pays Eve 6");
+        assert_eq!(second.len(), first.len());
+        context.update_words(&second);
+        mine(&mut context, &second, second_index, 3);
+
+        // longer, so a new buffer at a new address
+        let (third, third_index) = block(&"Bob pays Carol 7. ".repeat(8));
+        assert_ne!(third.len(), second.len());
+        context.update_words(&third);
+        mine(&mut context, &third, third_index, 3);
+    }
+
+    // the first nonce `search` finds, checked on the cpu
+    async fn search(
+        context: &mut Context,
+        words: &[u32],
+        nonce_index: u32,
+        k: u32,
+        hashing: Hashing,
+    ) -> u64 {
+        for generation in 0..16 {
+            let nonce = context
+                .search(words, nonce_index, k, hashing, generation)
+                .await;
+            if nonce != 0 {
+                let hash = hashing.hash_with_nonce(words, nonce_index, nonce);
+                assert!(util::hash_meets_k(&hash, k), "nonce {nonce}");
+                return nonce;
+            }
+        }
+        panic!("no nonce at k = {k} in 16 generations");
+    }
+
+    #[tokio::test]
+    async fn mines_sha256d() {
+        let (words, nonce_index) = block("Alice pays Bob 5");
+        let Some(mut context) = Context::for_tests(&words) else {
+            return;
+        };
+        search(&mut context, &words, nonce_index, 2, Hashing::Sha256d).await;
+    }
+
+    // the nonces either way meet k on the cpu
+    #[tokio::test]
+    async fn early_exit_agrees_with_the_full_digest() {
+        let (words, nonce_index) = block("Alice pays Bob 5");
+        let Some(mut context) = Context::for_tests(&words) else {
+            return;
+        };
+        for early_exit in [true, false] {
+            context.set_early_exit(early_exit);
+            for hashing in [Hashing::Sha256, Hashing::Sha256d] {
+                for k in [1, 2, 3] {
+                    search(&mut context, &words, nonce_index, k, hashing).await;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn specializes_per_shape() {
+        let (short, short_index) = block("Alice pays Bob 5");
+        let (long, long_index) = block(&"Bob pays Carol 7. ".repeat(8));
+        let Some(mut context) = Context::for_tests(&short) else {
+            return;
+        };
+        // back and forth between two shapes, the second visit to the first one reusing its pipeline
+        for (words, nonce_index) in [
+            (&short, short_index),
+            (&long, long_index),
+            (&short, short_index),
+        ] {
+            search(&mut context, words, nonce_index, 3, Hashing::Sha256).await;
+        }
+        assert_eq!(context.variants.len(), 2);
+
+        // the cache starts over once it's full
+        let shape = |k| JobShape {
+            block_count: short.len() as u32 / 16,
+            nonce_index: short_index,
+            k,
+            hashing: Hashing::Sha256,
+            early_exit: true,
+        };
+        let mut k = 4;
+        while context.variants.len() < MAX_VARIANTS {
+            context.pipeline_for(shape(k));
+            k += 1;
+        }
+        context.pipeline_for(shape(k));
+        assert_eq!(context.variants.len(), 1);
+        assert!(context.variants.contains_key(&shape(k)));
+        search(&mut context, &long, long_index, 3, Hashing::Sha256).await;
+        assert_eq!(context.variants.len(), 2);
     }
 }
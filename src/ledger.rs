@@ -0,0 +1,385 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::transaction::Transaction;
+use crate::util;
+
+// how balances are kept, chosen when a chain is started
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LedgerMode {
+    #[default]
+    Account,
+    Utxo,
+}
+
+impl Display for LedgerMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LedgerMode::Account => write!(f, "account"),
+            LedgerMode::Utxo => write!(f, "utxo"),
+        }
+    }
+}
+
+impl FromStr for LedgerMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "account" => Ok(LedgerMode::Account),
+            "utxo" => Ok(LedgerMode::Utxo),
+            _ => Err(format!(
+                "unknown ledger mode {value}, expected account or utxo"
+            )),
+        }
+    }
+}
+
+// reference to the output `index` of the transaction hashing to `txid`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OutPoint {
+    pub txid: [u32; 8],
+    pub index: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Output {
+    pub recipient: String,
+    pub value: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LedgerError {
+    UnknownInput(OutPoint),
+    DoubleSpend(OutPoint),
+    NotOwner(OutPoint),
+    MissingInputs,
+    UnexpectedInputs,
+    Unbalanced { available: u64, spent: u64 },
+    InsufficientBalance { balance: u64, spent: u64 },
+    CoinbaseFee,
+    ExcessiveCoinbase { paid: u64, allowed: u64 },
+    Overflow,
+}
+
+impl Display for LedgerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LedgerError::UnknownInput(outpoint) => write!(
+                f,
+                "input {}:{} does not exist or was already spent",
+                util::hash_to_hex(&outpoint.txid),
+                outpoint.index
+            ),
+            LedgerError::DoubleSpend(outpoint) => write!(
+                f,
+                "input {}:{} is spent twice",
+                util::hash_to_hex(&outpoint.txid),
+                outpoint.index
+            ),
+            LedgerError::NotOwner(outpoint) => write!(
+                f,
+                "input {}:{} doesn't belong to the sender",
+                util::hash_to_hex(&outpoint.txid),
+                outpoint.index
+            ),
+            LedgerError::MissingInputs => write!(f, "transaction spends no inputs"),
+            LedgerError::UnexpectedInputs => {
+                write!(f, "account ledger transactions can't spend inputs")
+            }
+            LedgerError::Unbalanced { available, spent } => write!(
+                f,
+                "inputs hold {available} but outputs and fee add up to {spent}"
+            ),
+            LedgerError::InsufficientBalance { balance, spent } => {
+                write!(f, "sender spends {spent} but only has {balance}")
+            }
+            LedgerError::CoinbaseFee => write!(f, "coinbase transactions can't pay a fee"),
+            LedgerError::ExcessiveCoinbase { paid, allowed } => {
+                write!(f, "coinbase pays out {paid} but only {allowed} is allowed")
+            }
+            LedgerError::Overflow => write!(f, "transaction values overflow"),
+        }
+    }
+}
+
+impl std::error::Error for LedgerError {}
+
+// serialized as a list since json maps only take string keys
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Vec<(OutPoint, Output)>", into = "Vec<(OutPoint, Output)>")]
+pub struct UtxoSet {
+    pub(crate) outputs: HashMap<OutPoint, Output>,
+}
+
+impl From<Vec<(OutPoint, Output)>> for UtxoSet {
+    fn from(entries: Vec<(OutPoint, Output)>) -> Self {
+        Self {
+            outputs: entries.into_iter().collect(),
+        }
+    }
+}
+
+impl From<UtxoSet> for Vec<(OutPoint, Output)> {
+    fn from(set: UtxoSet) -> Self {
+        set.outputs.into_iter().collect()
+    }
+}
+
+impl UtxoSet {
+    // inputs must add up to exactly the outputs plus the fee
+    pub fn check(&self, transaction: &Transaction) -> Result<(), LedgerError> {
+        if transaction.inputs.is_empty() {
+            return Err(LedgerError::MissingInputs);
+        }
+        let spent = spent(transaction)?;
+        let mut seen = HashSet::new();
+        let mut available: u64 = 0;
+        for input in &transaction.inputs {
+            if !seen.insert(*input) {
+                return Err(LedgerError::DoubleSpend(*input));
+            }
+            let output = self
+                .outputs
+                .get(input)
+                .ok_or(LedgerError::UnknownInput(*input))?;
+            if output.recipient != transaction.sender {
+                return Err(LedgerError::NotOwner(*input));
+            }
+            available = available
+                .checked_add(output.value)
+                .ok_or(LedgerError::Overflow)?;
+        }
+        if available != spent {
+            return Err(LedgerError::Unbalanced { available, spent });
+        }
+        Ok(())
+    }
+
+    fn insert_outputs(&mut self, transaction: &Transaction) {
+        let txid = transaction.hash();
+        for (index, output) in transaction.outputs.iter().enumerate() {
+            self.outputs.insert(
+                OutPoint {
+                    txid,
+                    index: index as u32,
+                },
+                output.clone(),
+            );
+        }
+    }
+
+    pub fn apply(&mut self, transaction: &Transaction) -> Result<(), LedgerError> {
+        self.check(transaction)?;
+        for input in &transaction.inputs {
+            self.outputs.remove(input);
+        }
+        self.insert_outputs(transaction);
+        Ok(())
+    }
+
+    pub fn balance(&self, recipient: &str) -> u64 {
+        self.outputs
+            .values()
+            .filter(|output| output.recipient == recipient)
+            .map(|output| output.value)
+            .sum()
+    }
+}
+
+// balances are keyed by sender address or recipient
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Balances {
+    pub(crate) balances: HashMap<String, u64>,
+}
+
+impl Balances {
+    pub fn check(&self, transaction: &Transaction) -> Result<(), LedgerError> {
+        if !transaction.inputs.is_empty() {
+            return Err(LedgerError::UnexpectedInputs);
+        }
+        let spent = spent(transaction)?;
+        let balance = self.balance(&transaction.sender);
+        if balance < spent {
+            return Err(LedgerError::InsufficientBalance { balance, spent });
+        }
+        Ok(())
+    }
+
+    fn credit(&mut self, transaction: &Transaction) {
+        for output in &transaction.outputs {
+            *self.balances.entry(output.recipient.clone()).or_default() += output.value;
+        }
+    }
+
+    pub fn apply(&mut self, transaction: &Transaction) -> Result<(), LedgerError> {
+        self.check(transaction)?;
+        *self.balances.entry(transaction.sender.clone()).or_default() -= spent(transaction)?;
+        self.credit(transaction);
+        Ok(())
+    }
+
+    pub fn balance(&self, name: &str) -> u64 {
+        self.balances.get(name).copied().unwrap_or_default()
+    }
+}
+
+// compared by content, not by the order the maps happen to be in
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Ledger {
+    Account(Balances),
+    Utxo(UtxoSet),
+}
+
+impl Ledger {
+    pub fn new(mode: LedgerMode) -> Self {
+        match mode {
+            LedgerMode::Account => Ledger::Account(Balances::default()),
+            LedgerMode::Utxo => Ledger::Utxo(UtxoSet::default()),
+        }
+    }
+
+    pub fn mode(&self) -> LedgerMode {
+        match self {
+            Ledger::Account(_) => LedgerMode::Account,
+            Ledger::Utxo(_) => LedgerMode::Utxo,
+        }
+    }
+
+    pub fn balance(&self, name: &str) -> u64 {
+        match self {
+            Ledger::Account(balances) => balances.balance(name),
+            Ledger::Utxo(utxos) => utxos.balance(name),
+        }
+    }
+
+    pub fn check(&self, transaction: &Transaction) -> Result<(), LedgerError> {
+        match self {
+            Ledger::Account(balances) => balances.check(transaction),
+            Ledger::Utxo(utxos) => utxos.check(transaction),
+        }
+    }
+
+    pub fn apply(&mut self, transaction: &Transaction) -> Result<(), LedgerError> {
+        match self {
+            Ledger::Account(balances) => balances.apply(transaction),
+            Ledger::Utxo(utxos) => utxos.apply(transaction),
+        }
+    }
+
+    // the coinbase creates the block reward plus the fees of the block out of nothing, but not a unit more
+    pub fn apply_coinbase(
+        &mut self,
+        transaction: &Transaction,
+        allowed: u64,
+    ) -> Result<(), LedgerError> {
+        if !transaction.inputs.is_empty() {
+            return Err(LedgerError::UnexpectedInputs);
+        }
+        if transaction.fee != 0 {
+            return Err(LedgerError::CoinbaseFee);
+        }
+        let paid = spent(transaction)?;
+        if paid > allowed {
+            return Err(LedgerError::ExcessiveCoinbase { paid, allowed });
+        }
+        match self {
+            Ledger::Account(balances) => balances.credit(transaction),
+            Ledger::Utxo(utxos) => utxos.insert_outputs(transaction),
+        }
+        Ok(())
+    }
+}
+
+// what a transaction takes from its sender: the outputs and the fee
+fn spent(transaction: &Transaction) -> Result<u64, LedgerError> {
+    transaction
+        .outputs
+        .iter()
+        .map(|output| output.value)
+        .chain(std::iter::once(transaction.fee))
+        .try_fold(0u64, |acc, value| {
+            acc.checked_add(value).ok_or(LedgerError::Overflow)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+    use crate::wallet;
+
+    // a utxo ledger holding a single coinbase output of 100 for `key`, and where that output is
+    fn funded(key: &SigningKey) -> (Ledger, OutPoint) {
+        let mut ledger = Ledger::new(LedgerMode::Utxo);
+        let coinbase = Transaction::coinbase(0, 100, key);
+        ledger.apply_coinbase(&coinbase, 100).unwrap();
+        let outpoint = OutPoint {
+            txid: coinbase.hash(),
+            index: 0,
+        };
+        (ledger, outpoint)
+    }
+
+    fn pay(key: &SigningKey, inputs: Vec<OutPoint>, value: u64) -> Transaction {
+        let recipient = "Alice".to_string();
+        Transaction::payment(1, inputs, vec![Output { recipient, value }], 1, key)
+    }
+
+    #[test]
+    fn spends_an_output_once() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let (mut ledger, outpoint) = funded(&key);
+        ledger.apply(&pay(&key, vec![outpoint], 99)).unwrap();
+        assert_eq!(ledger.balance("Alice"), 99);
+        assert_eq!(ledger.balance(&wallet::address(&key.verifying_key())), 0);
+        assert_eq!(
+            ledger.apply(&pay(&key, vec![outpoint], 99)),
+            Err(LedgerError::UnknownInput(outpoint))
+        );
+    }
+
+    #[test]
+    fn rejects_double_spends_within_a_transaction() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let (ledger, outpoint) = funded(&key);
+        assert_eq!(
+            ledger.check(&pay(&key, vec![outpoint, outpoint], 199)),
+            Err(LedgerError::DoubleSpend(outpoint))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_inputs() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let (ledger, outpoint) = funded(&key);
+        let unknown = OutPoint {
+            txid: outpoint.txid,
+            index: 1,
+        };
+        assert_eq!(
+            ledger.check(&pay(&key, vec![unknown], 99)),
+            Err(LedgerError::UnknownInput(unknown))
+        );
+        assert_eq!(
+            ledger.check(&pay(&key, vec![], 99)),
+            Err(LedgerError::MissingInputs)
+        );
+        assert_eq!(
+            ledger.check(&pay(&SigningKey::from_bytes(&[2; 32]), vec![outpoint], 99)),
+            Err(LedgerError::NotOwner(outpoint))
+        );
+        assert_eq!(
+            ledger.check(&pay(&key, vec![outpoint], 98)),
+            Err(LedgerError::Unbalanced {
+                available: 100,
+                spent: 99
+            })
+        );
+    }
+}
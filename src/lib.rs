@@ -0,0 +1,47 @@
+//! A toy blockchain mined on the GPU.
+//!
+//! The pieces are usable on their own:
+//!
+//! - hashing: [`util::sha256`] and [`util::pad`], the same SHA-256 the shader runs, and
+//!   [`util::hash_with_nonce`] for a block's words with a nonce filled in. [`util::Hashing`] picks
+//!   between that and Bitcoin's double SHA-256 per chain.
+//! - transactions: [`transaction::Transaction`], built with `coinbase`, `coinbase_to` or `payment` and
+//!   signed by a [`wallet`] key
+//! - verification: [`block::Block::meets_target`], [`transaction::Transaction::verify_signature`] and
+//!   [`chain::Chain`], which checks every block against its ledger
+//! - mining backends: the [`miner::Miner`] trait, implemented on the GPU by [`context::Context`] and on
+//!   the CPU by [`miner::CpuMiner`]
+//!
+//! On top of those sit a mempool, a gossiping [`node`] with a JSON-RPC server, and a mining [`pool`].
+//!
+//! The GPU backend is behind the default `gpu` feature. Without it the crate needs neither Vulkan nor the
+//! shader, and mines on the CPU.
+
+pub mod block;
+pub mod chain;
+pub mod config;
+#[cfg(feature = "gpu")]
+pub mod context;
+pub mod ledger;
+pub mod mempool;
+pub mod miner;
+pub mod mining;
+pub mod node;
+#[cfg(feature = "gpu")]
+mod pipeline_cache;
+pub mod pool;
+#[cfg(feature = "gpu")]
+mod push_constants;
+pub mod reward;
+pub mod rpc;
+#[cfg(feature = "gpu")]
+mod self_test;
+#[cfg(target_arch = "x86_64")]
+mod sha_ni;
+#[cfg(feature = "gpu")]
+mod shader;
+pub mod shares;
+pub mod simd;
+pub mod transaction;
+pub mod util;
+pub mod wallet;
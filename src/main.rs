@@ -1,166 +1,395 @@
-mod context;
-mod push_constants;
-mod shader;
-mod transaction;
-mod util;
-
-use crate::{
-    push_constants::PushConstants,
-    util::{check_k_nibbles, hash_to_iv, sha256},
+mod cli;
+
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use ed25519_dalek::SigningKey;
+use serde_json::json;
+use zeroize::Zeroizing;
+
+use toy_blockchain::{
+    block::Block,
+    chain::{Chain, block_work},
+    config::Config,
+    mining::{self, MiningStatus},
+    pool, rpc,
+    shares::ShareLog,
+    util::{self, hash_to_hex},
+    wallet::{self, Wallet, WalletError},
 };
 
-const K: u32 = 7;
+use crate::cli::{Cli, Command, Format, PoolCommand, WalletCommand};
 
-// TODO: while waiting on the gpu invocation, bruteforce on the cpu.
-// NOTE: do this with tokio select
-#[tokio::main]
-async fn main() {
-    let genesis_hash: [u32; 8];
-    let genesis_transaction = transaction::Transaction::default();
-    let padded = util::pad(genesis_transaction.to_string().as_bytes());
-    let mut words = util::to_words(&padded);
-    let nonce_index = genesis_transaction.nonce_offset();
-    let mut context = context::Context::new(&words);
-    let mut push_constants = PushConstants {
-        generation: 0,
-        word_count: words.len() as u32,
-        nonce_index: nonce_index,
-        words: context.words_address().into(),
-        nonce: context.nonce_address().into(),
-        k: K,
-    };
-    let mut hash_count: u64 = 0;
-    // genesis transaction
-    {
-        let mut nonce = 0;
-        while nonce == 0 {
-            nonce = context.invoke(&push_constants).await;
-            hash_count += 8192 * 64;
-            push_constants.generation += 1;
-        }
-        let nonce_high = (nonce >> 32) as u32;
-        let nonce_low = (nonce & 0xFFFFFFFF) as u32;
-        let idx = nonce_index as usize;
-        words[idx] = nonce_high;
-        words[idx + 1] = nonce_low;
-        let hash_u32 = sha256(&words);
-        let result: Vec<u8> = hash_u32.iter().flat_map(|&w| w.to_be_bytes()).collect();
-        genesis_hash = hash_to_iv(&result);
-        println!(
-            "hash: {h0:08x}{h1:08x}{h2:08x}{h3:08x}{h4:08x}{h5:08x}{h6:08x}{h7:08x}",
-            h0 = genesis_hash[0],
-            h1 = genesis_hash[1],
-            h2 = genesis_hash[2],
-            h3 = genesis_hash[3],
-            h4 = genesis_hash[4],
-            h5 = genesis_hash[5],
-            h6 = genesis_hash[6],
-            h7 = genesis_hash[7],
-        );
-        println!("nonce: {nonce}");
-        if check_k_nibbles(&result, K) {
-            println!("success! (genesis)");
-        } else {
-            println!("failure... (genesis)");
+// holds the rpc token, readable only by the user running the node
+const COOKIE_PATH: &str = ".cookie";
+
+fn print_json(value: &serde_json::Value) {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).expect("json values always serialize")
+    );
+}
+
+// mines a new chain and prints every block as it comes, then the balances
+async fn run_mine(
+    cli: &Cli,
+    config: &Config,
+    blocks: usize,
+    key: &SigningKey,
+) -> Result<(), Box<dyn Error>> {
+    let status = MiningStatus::new(config.k);
+    let text = cli.format == Format::Text;
+    let mut mined = vec![];
+    let chain = mining::mine_chain(config, blocks, key, &status, |block| {
+        let hash = hash_to_hex(&block.block.hash());
+        if text {
+            println!("hash: {hash}");
+            println!("nonce: {}", block.block.nonce());
+            if block.success {
+                println!("success! ({})", block.label);
+            } else {
+                println!("failure... ({})", block.label);
+            }
+        }
+        mined.push(json!({
+            "label": block.label,
+            "hash": hash,
+            "nonce": block.block.nonce(),
+            "success": block.success,
+        }));
+    })
+    .await?;
+
+    let sender = wallet::address(&key.verifying_key());
+    let names: Vec<&str> = std::iter::once(sender.as_str())
+        .chain(config.recipients.iter().map(String::as_str))
+        .collect();
+    match cli.format {
+        Format::Text => {
+            for name in names {
+                println!("balance of {name}: {}", chain.ledger().balance(name));
+            }
+            println!("total hashes: {}", status.hashes());
+        }
+        Format::Json => {
+            let balances: serde_json::Map<String, serde_json::Value> = names
+                .into_iter()
+                .map(|name| (name.to_string(), json!(chain.ledger().balance(name))))
+                .collect();
+            print_json(&json!({
+                "blocks": mined,
+                "balances": balances,
+                "hashes": status.hashes(),
+            }));
         }
     }
+    Ok(())
+}
 
-    let hash_2: [u32; 8];
-    let transaction_2 = transaction::Transaction::new(genesis_transaction, genesis_hash);
-    // 2. transaction
-    {
-        let padded = util::pad(transaction_2.to_string().as_bytes());
-        let mut words = util::to_words(&padded);
-        context.update_words(&words);
-        let nonce_index = transaction_2.nonce_offset();
-        let mut push_constants = PushConstants {
-            generation: 0,
-            word_count: words.len() as u32,
-            nonce_index: nonce_index,
-            words: context.words_address().into(),
-            nonce: context.nonce_address().into(),
-            k: K,
-        };
-
-        let mut nonce = 0;
-        while nonce == 0 {
-            nonce = context.invoke(&push_constants).await;
-            hash_count += 8192 * 64;
-            push_constants.generation += 1;
-        }
-        let nonce_high = (nonce >> 32) as u32;
-        let nonce_low = (nonce & 0xFFFFFFFF) as u32;
-        let idx = nonce_index as usize;
-        words[idx] = nonce_high;
-        words[idx + 1] = nonce_low;
-        let hash_u32 = sha256(&words);
-        let result: Vec<u8> = hash_u32.iter().flat_map(|&w| w.to_be_bytes()).collect();
-        hash_2 = hash_to_iv(&result);
-        println!(
-            "hash: {h0:08x}{h1:08x}{h2:08x}{h3:08x}{h4:08x}{h5:08x}{h6:08x}{h7:08x}",
-            h0 = hash_u32[0],
-            h1 = hash_u32[1],
-            h2 = hash_u32[2],
-            h3 = hash_u32[3],
-            h4 = hash_u32[4],
-            h5 = hash_u32[5],
-            h6 = hash_u32[6],
-            h7 = hash_u32[7],
-        );
-        println!("nonce: {nonce}");
-        if check_k_nibbles(&result, K) {
-            println!("success! (2.)");
-        } else {
-            println!("failure... (2.)");
+fn run_verify(cli: &Cli, config: &Config) -> Result<(), Box<dyn Error>> {
+    let chain = Chain::load(&config.chain)?;
+    let result = chain.verify();
+    match cli.format {
+        Format::Text => match &result {
+            Ok(()) => println!(
+                "{}: {} blocks, all valid",
+                config.chain.display(),
+                chain.height()
+            ),
+            Err((height, error)) => println!("{}: block {height}: {error}", config.chain.display()),
+        },
+        Format::Json => print_json(&match &result {
+            Ok(()) => json!({ "valid": true, "height": chain.height() }),
+            Err((height, error)) => {
+                json!({ "valid": false, "height": height, "error": error.to_string() })
+            }
+        }),
+    }
+    result.map_err(|(_, error)| error.into())
+}
+
+// hashes an empty block at a difficulty nothing meets for `seconds`
+async fn run_bench(cli: &Cli, config: &Config, seconds: u64) -> Result<(), Box<dyn Error>> {
+    let block = Block::new([0; 8], 64, vec![]).with_hashing(config.hashing);
+    let mut miner = config.miner(&block.words())?;
+    let status = MiningStatus::new(block.k());
+    let started = Instant::now();
+    mining::bench(&mut miner, &block, &status, Duration::from_secs(seconds)).await;
+    let hashrate = status.hashes() as f64 / started.elapsed().as_secs_f64();
+    match cli.format {
+        Format::Text => println!(
+            "{:?}, {}: {:.2} MH/s ({} hashes in {:.1}s)",
+            config.backend,
+            config.hashing,
+            hashrate / 1e6,
+            status.hashes(),
+            started.elapsed().as_secs_f64()
+        ),
+        Format::Json => print_json(&json!({
+            "backend": format!("{:?}", config.backend).to_lowercase(),
+            "hashing": config.hashing,
+            "hashes": status.hashes(),
+            "seconds": started.elapsed().as_secs_f64(),
+            "hashrate": hashrate,
+        })),
+    }
+    Ok(())
+}
+
+// what `list-devices` prints about every device
+#[cfg(feature = "gpu")]
+fn device_list() -> Result<Vec<serde_json::Value>, Box<dyn Error>> {
+    Ok(toy_blockchain::context::Context::devices()
+        .iter()
+        .enumerate()
+        .map(|(index, device)| {
+            let properties = device.properties();
+            json!({
+                "index": index,
+                "name": properties.device_name,
+                "type": format!("{:?}", properties.device_type),
+                "api_version": properties.api_version.to_string(),
+            })
+        })
+        .collect())
+}
+
+#[cfg(not(feature = "gpu"))]
+fn device_list() -> Result<Vec<serde_json::Value>, Box<dyn Error>> {
+    Err(toy_blockchain::miner::BackendError::GpuDisabled.into())
+}
+
+fn run_list_devices(cli: &Cli) -> Result<(), Box<dyn Error>> {
+    let devices = device_list()?;
+    match cli.format {
+        Format::Text => {
+            for device in &devices {
+                println!(
+                    "{}: {} ({}, vulkan {})",
+                    device["index"],
+                    device["name"].as_str().unwrap_or_default(),
+                    device["type"].as_str().unwrap_or_default(),
+                    device["api_version"].as_str().unwrap_or_default()
+                );
+            }
         }
+        Format::Json => print_json(&json!(devices)),
     }
-    // 3. transaction
-    {
-        let transaction_3 = transaction::Transaction::new(transaction_2.clone(), hash_2);
-        let padded = util::pad(transaction_3.to_string().as_bytes());
-        let mut words = util::to_words(&padded);
-        context.update_words(&words);
-        let nonce_index = transaction_3.nonce_offset();
-        let mut push_constants = PushConstants {
-            generation: 0,
-            word_count: words.len() as u32,
-            nonce_index: nonce_index,
-            words: context.words_address().into(),
-            nonce: context.nonce_address().into(),
-            k: K,
-        };
-
-        let mut nonce = 0;
-        while nonce == 0 {
-            nonce = context.invoke(&push_constants).await;
-            hash_count += 8192 * 64;
-            push_constants.generation += 1;
-        }
-        let nonce_high = (nonce >> 32) as u32;
-        let nonce_low = (nonce & 0xFFFFFFFF) as u32;
-        let idx = nonce_index as usize;
-        words[idx] = nonce_high;
-        words[idx + 1] = nonce_low;
-        let hash_u32 = sha256(&words);
-        let result: Vec<u8> = hash_u32.iter().flat_map(|&w| w.to_be_bytes()).collect();
-
-        println!(
-            "hash: {h0:08x}{h1:08x}{h2:08x}{h3:08x}{h4:08x}{h5:08x}{h6:08x}{h7:08x}",
-            h0 = hash_u32[0],
-            h1 = hash_u32[1],
-            h2 = hash_u32[2],
-            h3 = hash_u32[3],
-            h4 = hash_u32[4],
-            h5 = hash_u32[5],
-            h6 = hash_u32[6],
-            h7 = hash_u32[7],
-        );
-        println!("nonce: {nonce}");
-        if check_k_nibbles(&result, K) {
-            println!("success! (3.)");
-        } else {
-            println!("failure... (3.)");
+    Ok(())
+}
+
+fn run_show_chain(cli: &Cli, config: &Config) -> Result<(), Box<dyn Error>> {
+    let chain = Chain::load(&config.chain)?;
+    match cli.format {
+        Format::Text => {
+            for (height, block) in chain.blocks().iter().enumerate() {
+                println!(
+                    "{height:>6} {} k={} nonce={} transactions={}",
+                    hash_to_hex(&block.hash()),
+                    block.k(),
+                    block.nonce(),
+                    block.transactions().len()
+                );
+            }
+            println!("ledger: {}", chain.ledger().mode());
+            println!("hashing: {}", chain.hashing());
+            println!("work: {}", chain.work());
+        }
+        Format::Json => {
+            let blocks: Vec<serde_json::Value> = chain
+                .blocks()
+                .iter()
+                .enumerate()
+                .map(|(height, block)| {
+                    json!({ "height": height, "hash": hash_to_hex(&block.hash()), "block": block })
+                })
+                .collect();
+            // u128 doesn't fit a json number
+            print_json(&json!({
+                "blocks": blocks,
+                "ledger": chain.ledger().mode(),
+                "hashing": chain.hashing(),
+                "work": chain.work().to_string(),
+            }));
+        }
+    }
+    Ok(())
+}
+
+// pads the input like a block before hashing it, so the result is comparable with block hashes
+fn run_hash(cli: &Cli, config: &Config, input: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let data = match input {
+        Some(input) => input.as_bytes().to_vec(),
+        None => {
+            let mut data = vec![];
+            io::stdin().read_to_end(&mut data)?;
+            data
         }
+    };
+    let hash = hash_to_hex(&config.hashing.hash(&util::to_words(&util::pad(&data))));
+    match cli.format {
+        Format::Text => println!("{hash}"),
+        Format::Json => print_json(&json!({ "hash": hash })),
+    }
+    Ok(())
+}
+
+// syncs with the peers, then mines on top of the best known chain, gossiping every block found, until
+// stopped over rpc. the node keeps serving until ctrl-c.
+async fn run_node(
+    config: &Config,
+    listen: &str,
+    peers: &[String],
+    rpc_addr: &str,
+    key: &SigningKey,
+) -> Result<(), Box<dyn Error>> {
+    let node = mining::start_node(listen, peers, config).await?;
+
+    let status = Arc::new(MiningStatus::new(config.k));
+    let cookie_path = Path::new(COOKIE_PATH);
+    let rpc_addr = rpc::start(rpc_addr, cookie_path, node.clone(), status.clone()).await?;
+    eprintln!("rpc on {rpc_addr}, token in {COOKIE_PATH}");
+
+    mining::mine_on(&node, config, &status, key).await?;
+    eprintln!("mining stopped");
+    tokio::signal::ctrl_c().await?;
+    fs::remove_file(cookie_path)?;
+    Ok(())
+}
+
+// runs a node that doesn't mine itself but hands out jobs to pool clients until ctrl-c
+async fn run_pool(
+    config: &Config,
+    listen: &str,
+    pool_listen: &str,
+    peers: &[String],
+    key: SigningKey,
+) -> Result<(), Box<dyn Error>> {
+    let node = mining::start_node(listen, peers, config).await?;
+    let config = pool::Config::for_blocks(config.k);
+    let pool_addr = pool::Pool::start(pool_listen, node, key, config).await?;
+    eprintln!("pool on {pool_addr}");
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+// what the pool's share log says about every worker
+fn pool_stats(k: u32) -> Result<(), Box<dyn Error>> {
+    let log = ShareLog::load(Path::new(pool::SHARES_PATH))?;
+    print!("{}", log.report(pool::PAYOUT, block_work(pool::share_k(k))));
+    Ok(())
+}
+
+// runs a `wallet` subcommand on the wallet at `path`
+fn run_wallet(path: &Path, command: &WalletCommand) -> Result<(), WalletError> {
+    match command {
+        WalletCommand::Create => {
+            Wallet::create(path, &wallet::read_new_passphrase(wallet::PASSPHRASE_VAR)?)?;
+            println!("created {}", path.display());
+        }
+        WalletCommand::Unlock => {
+            let wallet =
+                Wallet::open(path)?.unlock(&wallet::read_passphrase("wallet passphrase: ")?)?;
+            for (name, _) in wallet.list() {
+                wallet.key(name)?;
+            }
+            println!("unlocked {} keys", wallet.list().len());
+        }
+        WalletCommand::AddKey { name } => {
+            let mut wallet =
+                Wallet::open(path)?.unlock(&wallet::read_passphrase("wallet passphrase: ")?)?;
+            let public_key = wallet.add_key(name)?;
+            println!("{name}: {}", wallet::address(&public_key));
+        }
+        WalletCommand::List => {
+            for (name, public_key) in Wallet::open(path)?.list() {
+                println!("{name}: {}", wallet::address(public_key));
+            }
+        }
+        WalletCommand::Export { name } => {
+            let wallet =
+                Wallet::open(path)?.unlock(&wallet::read_passphrase("wallet passphrase: ")?)?;
+            let key = wallet.key(name)?;
+            let secret = Zeroizing::new(hex::encode(key.as_bytes()));
+            println!("name: {name}");
+            println!("address: {}", wallet::address(&key.verifying_key()));
+            println!(
+                "public key: {}",
+                hex::encode(key.verifying_key().as_bytes())
+            );
+            println!("secret key: {}", secret.as_str());
+        }
+        WalletCommand::ChangePassphrase => {
+            let mut wallet =
+                Wallet::open(path)?.unlock(&wallet::read_passphrase("current passphrase: ")?)?;
+            wallet.change_passphrase(&wallet::read_new_passphrase(wallet::NEW_PASSPHRASE_VAR)?)?;
+            println!("changed the passphrase of {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+fn unlock(path: &Path) -> Result<SigningKey, Box<dyn Error>> {
+    wallet::unlock_default(path)
+        .map_err(|error| format!("failed to unlock the wallet: {error}").into())
+}
+
+async fn run(cli: &Cli) -> Result<(), Box<dyn Error>> {
+    let config = Config::resolve(cli.layer(), cli.config.as_deref())?;
+    let config = &config;
+    match &cli.command {
+        Command::Mine { blocks, .. } => {
+            run_mine(cli, config, *blocks, &unlock(&config.wallet)?).await
+        }
+        Command::Verify => run_verify(cli, config),
+        Command::Bench { seconds, .. } => run_bench(cli, config, *seconds).await,
+        Command::ListDevices => run_list_devices(cli),
+        Command::ShowChain => run_show_chain(cli, config),
+        Command::Hash { input, .. } => run_hash(cli, config, input.as_deref()),
+        Command::Wallet { command } => Ok(run_wallet(&config.wallet, command)?),
+        Command::Node {
+            listen, peers, rpc, ..
+        } => run_node(config, listen, peers, rpc, &unlock(&config.wallet)?).await,
+        Command::Pool {
+            command:
+                PoolCommand::Serve {
+                    listen,
+                    pool_listen,
+                    peers,
+                    ..
+                },
+        } => run_pool(config, listen, pool_listen, peers, unlock(&config.wallet)?).await,
+        Command::Pool {
+            command: PoolCommand::Stats { .. },
+        } => pool_stats(config.k),
+        Command::PoolClient { pool, worker, .. } => {
+            // the real words come with the first job, and so do the difficulty and the hashing
+            let mut miner = config.miner(&[0; 16])?;
+            let status = MiningStatus::new(0);
+            Ok(pool::run_client(pool.as_str(), worker, &mut miner, &status).await?)
+        }
+        Command::Config => {
+            match cli.format {
+                Format::Text => print!("{}", config.to_toml()),
+                Format::Json => print_json(&json!(config)),
+            }
+            Ok(())
+        }
+    }
+}
+
+// TODO: while waiting on the gpu invocation, bruteforce on the cpu.
+// NOTE: do this with tokio select
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(error) = run(&cli).await {
+        eprintln!("{error}");
+        std::process::exit(1);
     }
-    println!("total hashes (GPU): {hash_count}");
 }
@@ -0,0 +1,382 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+
+use crate::block::Block;
+use crate::chain::Chain;
+use crate::ledger::{LedgerError, OutPoint, Output};
+use crate::transaction::{SignatureError, Transaction};
+use crate::util;
+use crate::wallet;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Order {
+    // highest fee first, ties broken by arrival
+    Fee,
+    Arrival,
+}
+
+#[derive(Debug)]
+pub enum MempoolError {
+    Known([u32; 8]),
+    Confirmed([u32; 8]),
+    Conflict { input: OutPoint, txid: [u32; 8] },
+    Signature(SignatureError),
+    Ledger(LedgerError),
+}
+
+impl Display for MempoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MempoolError::Known(txid) => write!(
+                f,
+                "transaction {} is already in the mempool",
+                util::hash_to_hex(txid)
+            ),
+            MempoolError::Confirmed(txid) => write!(
+                f,
+                "transaction {} is already in the chain",
+                util::hash_to_hex(txid)
+            ),
+            MempoolError::Conflict { input, txid } => write!(
+                f,
+                "input {}:{} is already spent by pending transaction {}",
+                util::hash_to_hex(&input.txid),
+                input.index,
+                util::hash_to_hex(txid)
+            ),
+            MempoolError::Signature(error) => write!(f, "{error}"),
+            MempoolError::Ledger(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for MempoolError {}
+
+impl From<SignatureError> for MempoolError {
+    fn from(error: SignatureError) -> Self {
+        MempoolError::Signature(error)
+    }
+}
+
+impl From<LedgerError> for MempoolError {
+    fn from(error: LedgerError) -> Self {
+        MempoolError::Ledger(error)
+    }
+}
+
+struct Entry {
+    transaction: Transaction,
+    fee: u64,
+    // monotonic counter, so arrival order doesn't depend on the clock resolution
+    arrival: u64,
+    received: Instant,
+}
+
+// transactions waiting to be mined. only transactions spending confirmed outputs are accepted,
+// so every entry is valid on top of the current chain tip on its own.
+pub struct Mempool {
+    entries: HashMap<[u32; 8], Entry>,
+    spends: HashMap<OutPoint, [u32; 8]>,
+    order: Order,
+    expiry: Duration,
+    next_arrival: u64,
+}
+
+impl Mempool {
+    pub fn new(order: Order, expiry: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            spends: HashMap::new(),
+            order,
+            expiry,
+            next_arrival: 0,
+        }
+    }
+
+    pub fn submit(
+        &mut self,
+        transaction: Transaction,
+        chain: &Chain,
+    ) -> Result<[u32; 8], MempoolError> {
+        let txid = self.check(&transaction, chain)?;
+        let entry = Entry {
+            fee: transaction.fee,
+            transaction,
+            arrival: self.next_arrival,
+            received: Instant::now(),
+        };
+        self.next_arrival += 1;
+        self.insert(txid, entry);
+        Ok(txid)
+    }
+
+    fn check(&self, transaction: &Transaction, chain: &Chain) -> Result<[u32; 8], MempoolError> {
+        let txid = transaction.hash();
+        if self.entries.contains_key(&txid) {
+            return Err(MempoolError::Known(txid));
+        }
+        if chain.contains(&txid) {
+            return Err(MempoolError::Confirmed(txid));
+        }
+        for input in &transaction.inputs {
+            if let Some(&txid) = self.spends.get(input) {
+                return Err(MempoolError::Conflict {
+                    input: *input,
+                    txid,
+                });
+            }
+        }
+        transaction.verify_signature()?;
+        chain.ledger.check(transaction)?;
+        Ok(txid)
+    }
+
+    fn insert(&mut self, txid: [u32; 8], entry: Entry) {
+        for input in &entry.transaction.inputs {
+            self.spends.insert(*input, txid);
+        }
+        self.entries.insert(txid, entry);
+    }
+
+    pub fn get(&self, txid: &[u32; 8]) -> Option<&Transaction> {
+        self.entries.get(txid).map(|entry| &entry.transaction)
+    }
+
+    fn remove(&mut self, txid: &[u32; 8]) -> Option<Transaction> {
+        let entry = self.entries.remove(txid)?;
+        for input in &entry.transaction.inputs {
+            self.spends.remove(input);
+        }
+        Some(entry.transaction)
+    }
+
+    // the next block to mine: a coinbase paying `miner` and as many entries as fit, in pool order,
+    // checked against each other
+    pub fn template(
+        &self,
+        chain: &Chain,
+        k: u32,
+        max_transactions: usize,
+        miner: &SigningKey,
+    ) -> Block {
+        let recipient = wallet::address(&miner.verifying_key());
+        self.template_with(chain, k, max_transactions, miner, |value| {
+            vec![Output { recipient, value }]
+        })
+    }
+
+    // like `template`, but the coinbase outputs come from `split`, given the reward plus fees
+    pub fn template_with(
+        &self,
+        chain: &Chain,
+        k: u32,
+        max_transactions: usize,
+        miner: &SigningKey,
+        split: impl FnOnce(u64) -> Vec<Output>,
+    ) -> Block {
+        let mut entries: Vec<&Entry> = self.entries.values().collect();
+        match self.order {
+            Order::Fee => entries.sort_by_key(|entry| (Reverse(entry.fee), entry.arrival)),
+            Order::Arrival => entries.sort_by_key(|entry| entry.arrival),
+        }
+        let mut ledger = chain.ledger.clone();
+        let mut transactions = vec![];
+        let mut fees: u64 = 0;
+        for entry in entries {
+            if transactions.len() >= max_transactions {
+                break;
+            }
+            let Some(total) = fees.checked_add(entry.fee) else {
+                continue;
+            };
+            if ledger.apply(&entry.transaction).is_ok() {
+                transactions.push(entry.transaction.clone());
+                fees = total;
+            }
+        }
+        let reward = chain.schedule.reward(chain.height()).saturating_add(fees);
+        let coinbase = Transaction::coinbase_to(chain.height(), split(reward), miner);
+        transactions.insert(0, coinbase);
+        Block::new(chain.tip_hash(), k, transactions).with_hashing(chain.hashing)
+    }
+
+    // drops the transactions of a freshly mined block and every entry spending the same outputs
+    pub fn remove_mined(&mut self, block: &Block) {
+        for transaction in &block.transactions {
+            self.remove(&transaction.hash());
+            for input in &transaction.inputs {
+                if let Some(txid) = self.spends.get(input).copied() {
+                    self.remove(&txid);
+                }
+            }
+        }
+    }
+
+    // follows the chain over to another branch: the transactions of the blocks that left the chain come
+    // back, the ones of the blocks that joined it go, and whatever isn't valid on the new tip is dropped
+    pub fn reorganize(&mut self, disconnected: &[Block], connected: &[Block], chain: &Chain) {
+        for block in connected {
+            self.remove_mined(block);
+        }
+        let mut entries: Vec<Entry> = self.entries.drain().map(|(_, entry)| entry).collect();
+        entries.sort_by_key(|entry| entry.arrival);
+        self.spends.clear();
+        for block in disconnected {
+            // the coinbase is only valid in its own block
+            for transaction in block.transactions.iter().skip(1) {
+                let _ = self.submit(transaction.clone(), chain);
+            }
+        }
+        // the remaining entries keep their place in line and their expiry
+        for entry in entries {
+            if let Ok(txid) = self.check(&entry.transaction, chain) {
+                self.insert(txid, entry);
+            }
+        }
+    }
+
+    pub fn evict_expired(&mut self) {
+        let expired: Vec<[u32; 8]> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.received.elapsed() > self.expiry)
+            .map(|(txid, _)| *txid)
+            .collect();
+        for txid in expired {
+            self.remove(&txid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::LedgerMode;
+    use crate::reward::Schedule;
+    use crate::util::Hashing;
+
+    const SCHEDULE: Schedule = Schedule {
+        initial_reward: 1000,
+        halving_interval: 0,
+    };
+    const EXPIRY: Duration = Duration::from_secs(60);
+
+    // small k only, no need for the gpu
+    fn mine(block: &Block) -> Block {
+        (0..)
+            .map(|nonce| block.with_nonce(nonce))
+            .find(Block::meets_target)
+            .unwrap()
+    }
+
+    // a chain whose genesis coinbase pays the initial reward to `miner`
+    fn funded(mode: LedgerMode, miner: &SigningKey) -> Chain {
+        let mut chain = Chain::new(mode, SCHEDULE, Hashing::Sha256);
+        let coinbase = Transaction::coinbase(0, SCHEDULE.initial_reward, miner);
+        chain
+            .push(mine(&Block::new([0; 8], 1, vec![coinbase])))
+            .unwrap();
+        chain
+    }
+
+    fn pay(key: &SigningKey, inputs: Vec<OutPoint>, value: u64, fee: u64) -> Transaction {
+        let recipient = "Carol".to_string();
+        Transaction::payment(1, inputs, vec![Output { recipient, value }], fee, key)
+    }
+
+    fn template_fees(mempool: &Mempool, chain: &Chain, miner: &SigningKey) -> Vec<u64> {
+        let template = mempool.template(chain, 1, 16, miner);
+        // the coinbase comes first
+        template.transactions[1..]
+            .iter()
+            .map(|transaction| transaction.fee)
+            .collect()
+    }
+
+    #[test]
+    fn orders_by_fee_or_arrival() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let chain = funded(LedgerMode::Account, &alice);
+        let mut by_fee = Mempool::new(Order::Fee, EXPIRY);
+        let mut by_arrival = Mempool::new(Order::Arrival, EXPIRY);
+        // distinct values keep the txids apart
+        for (value, fee) in [(10, 1), (11, 3), (12, 2), (13, 3)] {
+            let transaction = pay(&alice, vec![], value, fee);
+            by_fee.submit(transaction.clone(), &chain).unwrap();
+            by_arrival.submit(transaction, &chain).unwrap();
+        }
+        assert_eq!(template_fees(&by_fee, &chain, &alice), [3, 3, 2, 1]);
+        assert_eq!(template_fees(&by_arrival, &chain, &alice), [1, 3, 2, 3]);
+
+        // equal fees go by arrival
+        let template = by_fee.template(&chain, 1, 16, &alice);
+        let arrival = |transaction: &Transaction| by_fee.entries[&transaction.hash()].arrival;
+        assert!(arrival(&template.transactions[1]) < arrival(&template.transactions[2]));
+
+        // only what the balance covers makes it into the block, the highest fees first
+        let mut mempool = Mempool::new(Order::Fee, EXPIRY);
+        for fee in [1, 2] {
+            mempool
+                .submit(pay(&alice, vec![], 600, fee), &chain)
+                .unwrap();
+        }
+        assert_eq!(template_fees(&mempool, &chain, &alice), [2]);
+    }
+
+    #[test]
+    fn evicts_conflicting_transactions() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let mut chain = funded(LedgerMode::Utxo, &alice);
+        let outpoint = OutPoint {
+            txid: chain.blocks()[0].transactions[0].hash(),
+            index: 0,
+        };
+        let mut mempool = Mempool::new(Order::Fee, EXPIRY);
+        let pending = mempool
+            .submit(pay(&alice, vec![outpoint], 999, 1), &chain)
+            .unwrap();
+        assert!(matches!(
+            mempool.submit(pay(&alice, vec![outpoint], 998, 2), &chain),
+            Err(MempoolError::Conflict { txid, .. }) if txid == pending
+        ));
+        assert!(matches!(
+            mempool.submit(mempool.get(&pending).unwrap().clone(), &chain),
+            Err(MempoolError::Known(_))
+        ));
+
+        // a block spending the same output some other way takes the pending transaction with it
+        let coinbase = Transaction::coinbase(1, SCHEDULE.initial_reward + 3, &alice);
+        let spend = pay(&alice, vec![outpoint], 997, 3);
+        let block = mine(&Block::new(chain.tip_hash(), 1, vec![coinbase, spend]));
+        chain.push(block.clone()).unwrap();
+        mempool.remove_mined(&block);
+        assert!(mempool.get(&pending).is_none());
+        assert!(mempool.spends.is_empty());
+        assert!(matches!(
+            mempool.submit(block.transactions[1].clone(), &chain),
+            Err(MempoolError::Confirmed(_))
+        ));
+    }
+
+    #[test]
+    fn expires_old_transactions() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let chain = funded(LedgerMode::Account, &alice);
+        let mut kept = Mempool::new(Order::Fee, EXPIRY);
+        let mut expired = Mempool::new(Order::Fee, Duration::ZERO);
+        let transaction = pay(&alice, vec![], 10, 1);
+        let txid = kept.submit(transaction.clone(), &chain).unwrap();
+        expired.submit(transaction, &chain).unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+        kept.evict_expired();
+        expired.evict_expired();
+        assert!(kept.get(&txid).is_some());
+        assert!(expired.get(&txid).is_none());
+        assert_eq!(template_fees(&expired, &chain, &alice), [0u64; 0]);
+    }
+}
@@ -0,0 +1,173 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::str::FromStr;
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "gpu")]
+use crate::context::Context;
+use crate::simd::{Job, Kernel};
+use crate::util::Hashing;
+
+// nonces a cpu thread takes at a time
+const CPU_CHUNK: u64 = 4096;
+
+// has to match numthreads in the shader
+pub(crate) const WORKGROUP_SIZE: u64 = 128;
+// how far the nonce moves from one generation to the next, fixed in the shader. every miner covers exactly
+// these nonces per generation, so generations neither overlap nor leave gaps.
+pub const GENERATION_STRIDE: u64 = 64 * 8192;
+// the work groups that cover one generation. fewer would skip nonces and more would hash the start of the
+// next generation again.
+pub const MIN_WORK_GROUPS: u32 = (GENERATION_STRIDE / WORKGROUP_SIZE) as u32;
+// the only counts the config accepts
+pub const WORK_GROUP_COUNTS: [u32; 3] = [MIN_WORK_GROUPS, 1, 1];
+
+#[derive(Debug)]
+pub enum BackendError {
+    // `--device` didn't match any device, or there are none if it's empty
+    UnknownDevice(String),
+    // built without the `gpu` feature
+    GpuDisabled,
+    // the gpu hashed something differently than the cpu
+    SelfTest(String),
+}
+
+impl Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendError::UnknownDevice(device) if device.is_empty() => {
+                write!(f, "no devices available")
+            }
+            BackendError::UnknownDevice(device) => write!(f, "no device matches `{device}`"),
+            BackendError::GpuDisabled => write!(f, "built without gpu support"),
+            BackendError::SelfTest(error) => write!(f, "gpu self-test failed: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+// searches the nonces of one generation at a time, the unit the gpu works in
+pub trait Miner {
+    // a nonce of `generation` whose `hashing` hash has at least `k` leading zero nibbles, or 0 if there
+    // is none
+    fn search(
+        &mut self,
+        words: &[u32],
+        nonce_index: u32,
+        k: u32,
+        hashing: Hashing,
+        generation: u64,
+    ) -> impl Future<Output = u64>;
+}
+
+// does what the shader does on every core, in as many simd lanes as the cpu has. still orders of magnitude
+// slower, but needs no gpu.
+pub struct CpuMiner;
+
+impl Miner for CpuMiner {
+    async fn search(
+        &mut self,
+        words: &[u32],
+        nonce_index: u32,
+        k: u32,
+        hashing: Hashing,
+        generation: u64,
+    ) -> u64 {
+        let job = Job::new(words, nonce_index, hashing);
+        let kernel = Kernel::detect();
+        let start = generation * GENERATION_STRIDE;
+        let end = start + GENERATION_STRIDE;
+        // keep the runtime responsive while the cores are busy
+        tokio::task::spawn_blocking(move || {
+            // 0 means nothing was found, so it can't be a result
+            (start.max(1)..end)
+                .step_by(CPU_CHUNK as usize)
+                .par_bridge()
+                .find_map_any(|chunk| job.search(kernel, chunk..(chunk + CPU_CHUNK).min(end), k))
+                .unwrap_or(0)
+        })
+        .await
+        .unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    // the compute shader, through vulkan
+    Gpu,
+    // `CpuMiner`
+    Cpu,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "gpu" => Ok(Backend::Gpu),
+            "cpu" => Ok(Backend::Cpu),
+            _ => Err(format!("unknown backend {value}, expected gpu or cpu")),
+        }
+    }
+}
+
+// whichever miner the command line picked
+pub enum AnyMiner {
+    #[cfg(feature = "gpu")]
+    Gpu(Box<Context>),
+    Cpu(CpuMiner),
+}
+
+impl AnyMiner {
+    // `device` and `work_group_counts` only matter for the gpu. `words` are the first ones it mines on.
+    pub fn new(
+        backend: Backend,
+        device: Option<&str>,
+        work_group_counts: [u32; 3],
+        words: &[u32],
+    ) -> Result<Self, BackendError> {
+        Ok(match backend {
+            #[cfg(feature = "gpu")]
+            Backend::Gpu => AnyMiner::Gpu(Box::new(Context::on_device(
+                words,
+                device,
+                work_group_counts,
+            )?)),
+            #[cfg(not(feature = "gpu"))]
+            Backend::Gpu => {
+                let _ = (device, work_group_counts, words);
+                return Err(BackendError::GpuDisabled);
+            }
+            Backend::Cpu => AnyMiner::Cpu(CpuMiner),
+        })
+    }
+}
+
+impl Miner for AnyMiner {
+    async fn search(
+        &mut self,
+        words: &[u32],
+        nonce_index: u32,
+        k: u32,
+        hashing: Hashing,
+        generation: u64,
+    ) -> u64 {
+        match self {
+            #[cfg(feature = "gpu")]
+            AnyMiner::Gpu(context) => {
+                context
+                    .search(words, nonce_index, k, hashing, generation)
+                    .await
+            }
+            AnyMiner::Cpu(miner) => {
+                miner
+                    .search(words, nonce_index, k, hashing, generation)
+                    .await
+            }
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use std::fmt::Display;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use ed25519_dalek::SigningKey;
+use tokio::net::ToSocketAddrs;
+use tokio_util::sync::CancellationToken;
+
+use crate::block::Block;
+use crate::chain::Chain;
+use crate::config::Config;
+use crate::ledger::LedgerError;
+use crate::mempool::{Mempool, Order};
+use crate::miner::{BackendError, GENERATION_STRIDE, Miner};
+use crate::node::{self, Node, State};
+use crate::reward::Schedule;
+use crate::transaction::Transaction;
+use crate::util;
+
+pub const SCHEDULE: Schedule = Schedule {
+    initial_reward: 1000,
+    halving_interval: 210,
+};
+pub const MEMPOOL_EXPIRY: Duration = Duration::from_secs(60 * 60);
+pub const MEMPOOL_ORDER: Order = Order::Fee;
+pub const MAX_BLOCK_TRANSACTIONS: usize = 64;
+
+#[derive(Debug)]
+pub enum MiningError {
+    // `mine_chain` only starts new chains
+    ChainExists(PathBuf),
+    Io(io::Error),
+    Ledger(LedgerError),
+    Backend(BackendError),
+}
+
+impl Display for MiningError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MiningError::ChainExists(path) => write!(
+                f,
+                "{} already exists, move it away to start a new chain",
+                path.display()
+            ),
+            MiningError::Io(error) => write!(f, "{error}"),
+            MiningError::Ledger(error) => write!(f, "{error}"),
+            MiningError::Backend(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for MiningError {}
+
+impl From<io::Error> for MiningError {
+    fn from(error: io::Error) -> Self {
+        MiningError::Io(error)
+    }
+}
+
+impl From<LedgerError> for MiningError {
+    fn from(error: LedgerError) -> Self {
+        MiningError::Ledger(error)
+    }
+}
+
+impl From<BackendError> for MiningError {
+    fn from(error: BackendError) -> Self {
+        MiningError::Backend(error)
+    }
+}
+
+// a block `mine_chain` mined, with `label` naming it like the coursework does
+pub struct Mined {
+    pub label: String,
+    pub block: Block,
+    // whether the hash has its k zero nibbles
+    pub success: bool,
+}
+
+// what the miner is up to, shared with whoever wants to watch or stop it
+pub struct MiningStatus {
+    k: u32,
+    started: Instant,
+    hashes: AtomicU64,
+    // generation of the current gpu invocation, counted from the start of the current block
+    generation: AtomicU64,
+    stop: CancellationToken,
+}
+
+impl MiningStatus {
+    pub fn new(k: u32) -> Self {
+        Self {
+            k,
+            started: Instant::now(),
+            hashes: AtomicU64::new(0),
+            generation: AtomicU64::new(0),
+            stop: CancellationToken::new(),
+        }
+    }
+
+    pub fn record(&self, generation: u64, hashes: u64) {
+        self.generation.store(generation, Ordering::Relaxed);
+        self.hashes.fetch_add(hashes, Ordering::Relaxed);
+    }
+
+    pub fn k(&self) -> u32 {
+        self.k
+    }
+
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    // average hashes per second since mining started
+    pub fn hashrate(&self) -> f64 {
+        self.hashes() as f64 / self.started.elapsed().as_secs_f64()
+    }
+
+    // the miner finishes its current invocation and gives up
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop.is_cancelled()
+    }
+}
+
+// mines `block` with `miner` and returns it with the found nonce filled in. gives up once `is_stale` says
+// the block has been overtaken, which is checked between invocations.
+pub async fn mine(
+    miner: &mut impl Miner,
+    block: &Block,
+    status: &MiningStatus,
+    is_stale: impl Fn() -> bool,
+) -> Option<Block> {
+    let words = block.words();
+    let nonce_index = block.nonce_offset();
+    let mut generation = 0;
+    loop {
+        if is_stale() {
+            return None;
+        }
+        let nonce = miner
+            .search(&words, nonce_index, block.k(), block.hashing(), generation)
+            .await;
+        status.record(generation, GENERATION_STRIDE);
+        if nonce != 0 {
+            return Some(block.with_nonce(nonce));
+        }
+        generation += 1;
+    }
+}
+
+// starts a new chain with the coursework transactions, one per block after the genesis block, and saves it
+// to `config.chain`. `on_block` sees every block as it is mined. an existing chain file is left alone,
+// `mine_on` is what mines on top of one.
+pub async fn mine_chain(
+    config: &Config,
+    blocks: usize,
+    key: &SigningKey,
+    status: &MiningStatus,
+    mut on_block: impl FnMut(&Mined),
+) -> Result<Chain, MiningError> {
+    if config.chain.exists() {
+        return Err(MiningError::ChainExists(config.chain.clone()));
+    }
+    let k = config.k;
+    let coursework = config.coursework();
+    let mut chain = Chain::new(config.ledger, SCHEDULE, config.hashing);
+    let mut mempool = Mempool::new(MEMPOOL_ORDER, MEMPOOL_EXPIRY);
+
+    // the genesis transaction spends from the coinbase of its own block, which can't go through the
+    // mempool. its fee is left unclaimed since the coinbase has to exist first.
+    let coinbase = Transaction::coinbase(chain.height(), SCHEDULE.reward(chain.height()), key);
+    let mut transaction = Transaction::genesis(config.ledger, &coursework, &coinbase, key)?;
+    let mut block = Block::new(chain.tip_hash(), k, vec![coinbase, transaction.clone()])
+        .with_hashing(chain.hashing());
+    let mut miner = config.miner(&block.words())?;
+    for i in 0..blocks {
+        let label = if i == 0 {
+            "genesis".to_string()
+        } else {
+            format!("{}.", i + 1)
+        };
+        if i > 0 {
+            transaction = Transaction::new(config.ledger, &coursework, &transaction, key);
+            if let Err(error) = mempool.submit(transaction.clone(), &chain) {
+                eprintln!("rejected by the mempool ({label}): {error}");
+                break;
+            }
+            mempool.evict_expired();
+            block = mempool.template(&chain, k, MAX_BLOCK_TRANSACTIONS, key);
+        }
+        block = mine(&mut miner, &block, status, || false)
+            .await
+            .expect("a block that can't go stale is always mined");
+        let hash: Vec<u8> = block.hash().iter().flat_map(|&w| w.to_be_bytes()).collect();
+        on_block(&Mined {
+            success: util::check_k_nibbles(&hash, k),
+            label: label.clone(),
+            block: block.clone(),
+        });
+        if let Err(error) = chain.push(block.clone()) {
+            eprintln!("rejected by the chain ({label}): {error}");
+            break;
+        }
+        mempool.remove_mined(&block);
+    }
+    chain.save(&config.chain)?;
+    Ok(chain)
+}
+
+// starts a node on the chain of `config` and connects it to `peers`. the ledger mode and hashing of the
+// config are only used if the chain is new.
+pub async fn start_node(
+    listen: impl ToSocketAddrs,
+    peers: &[String],
+    config: &Config,
+) -> Result<Node, MiningError> {
+    let state = State {
+        chain: Chain::open(&config.chain, config.ledger, SCHEDULE, config.hashing)?,
+        mempool: Mempool::new(MEMPOOL_ORDER, MEMPOOL_EXPIRY),
+    };
+    let config = node::Config {
+        min_k: config.k,
+        chain_path: Some(config.chain.clone()),
+    };
+    let node = Node::start(listen, state, config).await?;
+    eprintln!("listening on {}", node.local_addr());
+    for peer in peers {
+        if let Err(error) = node.connect(peer.as_str()).await {
+            eprintln!("failed to connect to {peer}: {error}");
+        }
+    }
+    Ok(node)
+}
+
+// mines on top of the node's best chain with the miner and k of `config`, paid to `key`, submitting every
+// block found, until `status` is stopped. a block is given up as soon as the tip moves.
+pub async fn mine_on(
+    node: &Node,
+    config: &Config,
+    status: &MiningStatus,
+    key: &SigningKey,
+) -> Result<(), MiningError> {
+    let mut block = node.template(config.k, MAX_BLOCK_TRANSACTIONS, key);
+    let mut miner = config.miner(&block.words())?;
+    while !status.is_stopped() {
+        let tip = block.iv();
+        if let Some(mined) = mine(&mut miner, &block, status, || {
+            node.tip_hash() != tip || status.is_stopped()
+        })
+        .await
+        {
+            match node.submit_block(mined.clone()) {
+                Ok(()) => eprintln!("mined block {}", util::hash_to_hex(&mined.hash())),
+                Err(error) => eprintln!("mined block was rejected: {error}"),
+            }
+        }
+        block = node.template(config.k, MAX_BLOCK_TRANSACTIONS, key);
+    }
+    Ok(())
+}
+
+// hashes `block` at its k for `duration`, counting into `status`
+pub async fn bench(
+    miner: &mut impl Miner,
+    block: &Block,
+    status: &MiningStatus,
+    duration: Duration,
+) {
+    let words = block.words();
+    let started = Instant::now();
+    let mut generation = 0;
+    while started.elapsed() < duration {
+        miner
+            .search(
+                &words,
+                block.nonce_offset(),
+                block.k(),
+                block.hashing(),
+                generation,
+            )
+            .await;
+        status.record(generation, GENERATION_STRIDE);
+        generation += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::LedgerMode;
+    use crate::miner::Backend;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mines_a_new_chain_once() {
+        let path = std::env::temp_dir().join(format!("mining-test-{}.json", std::process::id()));
+        let config = Config {
+            k: 1,
+            ledger: LedgerMode::Utxo,
+            backend: Backend::Cpu,
+            chain: path.clone(),
+            ..Config::default()
+        };
+        let key = SigningKey::from_bytes(&[5; 32]);
+        let status = MiningStatus::new(config.k);
+        let mut labels = vec![];
+        let chain = mine_chain(&config, 3, &key, &status, |mined| {
+            assert!(mined.success);
+            labels.push(mined.label.clone());
+        })
+        .await
+        .unwrap();
+        assert_eq!(labels, ["genesis", "2.", "3."]);
+        assert_eq!(chain.height(), 3);
+        assert_eq!(Chain::load(&path).unwrap().tip_hash(), chain.tip_hash());
+
+        // the saved chain is left alone
+        let again = mine_chain(&config, 1, &key, &status, |_| {}).await;
+        assert!(matches!(again, Err(MiningError::ChainExists(_))));
+        assert_eq!(Chain::load(&path).unwrap().height(), 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
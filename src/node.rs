@@ -0,0 +1,563 @@
+use std::fmt::Display;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use ed25519_dalek::SigningKey;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{broadcast, watch};
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+
+use crate::block::Block;
+use crate::chain::{Accepted, Chain, ChainError};
+use crate::ledger::Output;
+use crate::mempool::{Mempool, MempoolError};
+use crate::transaction::Transaction;
+use crate::util;
+
+const PROTOCOL_VERSION: u32 = 2;
+// upper bound on the blocks sent in reply to a single getblocks
+const MAX_BLOCKS_PER_MESSAGE: usize = 500;
+const GOSSIP_CAPACITY: usize = 256;
+// longer lines drop the peer. a reply of MAX_BLOCKS_PER_MESSAGE full blocks stays well below it.
+const MAX_MESSAGE_LENGTH: usize = 64 << 20;
+// peer id of everything that didn't come in over the network
+const LOCAL: u64 = 0;
+
+// one json object per line
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Version {
+        version: u32,
+        height: u64,
+        tip: [u32; 8],
+    },
+    Verack,
+    // asks for the blocks following the last locator hash the peer has in common with the sender
+    GetBlocks {
+        locator: Vec<[u32; 8]>,
+    },
+    Blocks {
+        blocks: Vec<Block>,
+    },
+    Block {
+        block: Box<Block>,
+    },
+    Transaction {
+        transaction: Box<Transaction>,
+    },
+}
+
+#[derive(Debug)]
+pub enum NodeError {
+    Io(io::Error),
+    Protocol(String),
+    // blocks mined for fewer nibbles than the network asks for
+    TooEasy { k: u32, min_k: u32 },
+    Chain(ChainError),
+    Mempool(MempoolError),
+}
+
+impl Display for NodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeError::Io(error) => write!(f, "{error}"),
+            NodeError::Protocol(reason) => write!(f, "protocol error: {reason}"),
+            NodeError::TooEasy { k, min_k } => {
+                write!(
+                    f,
+                    "block was mined for k = {k}, at least {min_k} is required"
+                )
+            }
+            NodeError::Chain(error) => write!(f, "{error}"),
+            NodeError::Mempool(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for NodeError {}
+
+impl From<io::Error> for NodeError {
+    fn from(error: io::Error) -> Self {
+        NodeError::Io(error)
+    }
+}
+
+impl From<LinesCodecError> for NodeError {
+    fn from(error: LinesCodecError) -> Self {
+        match error {
+            LinesCodecError::Io(error) => NodeError::Io(error),
+            LinesCodecError::MaxLineLengthExceeded => {
+                NodeError::Protocol("message too long".to_string())
+            }
+        }
+    }
+}
+
+impl From<ChainError> for NodeError {
+    fn from(error: ChainError) -> Self {
+        NodeError::Chain(error)
+    }
+}
+
+impl From<MempoolError> for NodeError {
+    fn from(error: MempoolError) -> Self {
+        NodeError::Mempool(error)
+    }
+}
+
+pub struct State {
+    pub chain: Chain,
+    pub mempool: Mempool,
+}
+
+pub struct Config {
+    pub min_k: u32,
+    // where the chain is saved after every accepted block, if anywhere
+    pub chain_path: Option<PathBuf>,
+}
+
+// a handle to a running node. clones share the same state and peers.
+#[derive(Clone)]
+pub struct Node {
+    state: Arc<Mutex<State>>,
+    // accepted blocks and transactions, tagged with the peer they came from so they aren't echoed back
+    gossip: broadcast::Sender<(u64, Message)>,
+    next_peer: Arc<AtomicU64>,
+    config: Arc<Config>,
+    // the chain serialized after the last accepted block, written to `chain_path` in the background so the
+    // state isn't locked while it goes to disk
+    saves: Option<watch::Sender<Arc<Vec<u8>>>>,
+    local_addr: SocketAddr,
+}
+
+impl Node {
+    // listens on `addr` and accepts peers in the background
+    pub async fn start(addr: impl ToSocketAddrs, state: State, config: Config) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let (gossip, _) = broadcast::channel(GOSSIP_CAPACITY);
+        let node = Self {
+            state: Arc::new(Mutex::new(state)),
+            gossip,
+            next_peer: Arc::new(AtomicU64::new(LOCAL + 1)),
+            saves: config.chain_path.clone().map(save_in_background),
+            config: Arc::new(config),
+            local_addr: listener.local_addr()?,
+        };
+        let accepting = node.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(accepting.clone().run_peer(stream));
+                    }
+                    Err(error) => eprintln!("node: failed to accept a peer: {error}"),
+                }
+            }
+        });
+        Ok(node)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub async fn connect(&self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let stream = TcpStream::connect(addr).await?;
+        tokio::spawn(self.clone().run_peer(stream));
+        Ok(())
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn tip_hash(&self) -> [u32; 8] {
+        self.state.lock().unwrap().chain.tip_hash()
+    }
+
+    pub fn template(&self, k: u32, max_transactions: usize, miner: &SigningKey) -> Block {
+        let mut state = self.state.lock().unwrap();
+        state.mempool.evict_expired();
+        state
+            .mempool
+            .template(&state.chain, k, max_transactions, miner)
+    }
+
+    // a template whose coinbase outputs come from `split`, given the reward plus fees
+    pub fn template_with(
+        &self,
+        k: u32,
+        max_transactions: usize,
+        miner: &SigningKey,
+        split: impl FnOnce(u64) -> Vec<Output>,
+    ) -> Block {
+        let mut state = self.state.lock().unwrap();
+        state.mempool.evict_expired();
+        state
+            .mempool
+            .template_with(&state.chain, k, max_transactions, miner, split)
+    }
+
+    // adds a block mined by this node and announces it to every peer
+    pub fn submit_block(&self, block: Block) -> Result<(), NodeError> {
+        self.accept_block(block, LOCAL)
+    }
+
+    fn accept_block(&self, block: Block, origin: u64) -> Result<(), NodeError> {
+        if block.k < self.config.min_k {
+            return Err(NodeError::TooEasy {
+                k: block.k,
+                min_k: self.config.min_k,
+            });
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            match state.chain.push(block.clone())? {
+                Accepted::Extended => state.mempool.remove_mined(&block),
+                Accepted::Side => {}
+                Accepted::Reorganized {
+                    disconnected,
+                    connected,
+                } => {
+                    eprintln!(
+                        "node: reorganized, {} blocks left the chain and {} joined it",
+                        disconnected.len(),
+                        connected.len()
+                    );
+                    state
+                        .mempool
+                        .reorganize(&disconnected, &connected, &state.chain);
+                }
+            }
+            // serialized under the lock, so the saves go out in the order the blocks came in
+            if let Some(saves) = &self.saves {
+                match serde_json::to_vec(&state.chain) {
+                    Ok(data) => {
+                        saves.send_replace(Arc::new(data));
+                    }
+                    Err(error) => eprintln!("node: failed to save the chain: {error}"),
+                }
+            }
+        }
+        // side blocks are relayed too, the branch might win elsewhere. nobody listening is fine.
+        let _ = self.gossip.send((
+            origin,
+            Message::Block {
+                block: Box::new(block),
+            },
+        ));
+        Ok(())
+    }
+
+    // adds a transaction to the mempool and announces it to every peer
+    pub fn submit_transaction(&self, transaction: Transaction) -> Result<[u32; 8], NodeError> {
+        self.accept_transaction(transaction, LOCAL)
+    }
+
+    fn accept_transaction(
+        &self,
+        transaction: Transaction,
+        origin: u64,
+    ) -> Result<[u32; 8], NodeError> {
+        let txid = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            state.mempool.submit(transaction.clone(), &state.chain)?
+        };
+        let _ = self.gossip.send((
+            origin,
+            Message::Transaction {
+                transaction: Box::new(transaction),
+            },
+        ));
+        Ok(txid)
+    }
+
+    fn version(&self) -> Message {
+        let state = self.state.lock().unwrap();
+        Message::Version {
+            version: PROTOCOL_VERSION,
+            height: state.chain.height(),
+            tip: state.chain.tip_hash(),
+        }
+    }
+
+    fn get_blocks(&self) -> Message {
+        Message::GetBlocks {
+            locator: self.state.lock().unwrap().chain.locator(),
+        }
+    }
+
+    async fn run_peer(self, stream: TcpStream) {
+        let id = self.next_peer.fetch_add(1, Ordering::Relaxed);
+        let addr = stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        eprintln!("node: peer {id} connected ({addr})");
+        if let Err(error) = self.serve_peer(id, stream).await {
+            eprintln!("node: peer {id} dropped: {error}");
+        } else {
+            eprintln!("node: peer {id} disconnected");
+        }
+    }
+
+    async fn serve_peer(&self, id: u64, stream: TcpStream) -> Result<(), NodeError> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines =
+            FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_MESSAGE_LENGTH));
+        let mut gossip = self.gossip.subscribe();
+        // the handshake is the peer's version and its verack of ours. anything else before both drops the
+        // peer, and nothing is relayed to it until then.
+        let mut handshake = Handshake::default();
+
+        send(&mut writer, &self.version()).await?;
+        loop {
+            tokio::select! {
+                line = lines.next() => {
+                    let Some(line) = line else {
+                        return Ok(());
+                    };
+                    let message: Message = serde_json::from_str(&line?)
+                        .map_err(|error| NodeError::Protocol(error.to_string()))?;
+                    handshake.check(&message)?;
+                    for reply in self.handle(id, message)? {
+                        send(&mut writer, &reply).await?;
+                    }
+                }
+                relayed = gossip.recv() => match relayed {
+                    Ok((origin, message)) if handshake.is_done() && origin != id => {
+                        send(&mut writer, &message).await?;
+                    }
+                    Ok(_) => {}
+                    // the peer misses some gossip, it catches up on the next block it can't connect
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                }
+            }
+        }
+    }
+
+    // returns the replies to `message`
+    fn handle(&self, id: u64, message: Message) -> Result<Vec<Message>, NodeError> {
+        match message {
+            Message::Version {
+                version,
+                height,
+                tip,
+            } => {
+                if version != PROTOCOL_VERSION {
+                    return Err(NodeError::Protocol(format!(
+                        "unsupported protocol version {version}"
+                    )));
+                }
+                let mut replies = vec![Message::Verack];
+                // fork choice sorts out which branch wins once we have the peer's blocks
+                if !self.state.lock().unwrap().chain.knows(&tip) {
+                    eprintln!(
+                        "node: peer {id} is at height {height} (tip {}), syncing",
+                        util::hash_to_hex(&tip)
+                    );
+                    replies.push(self.get_blocks());
+                }
+                Ok(replies)
+            }
+            Message::Verack => Ok(vec![]),
+            Message::GetBlocks { locator } => {
+                let state = self.state.lock().unwrap();
+                let blocks = state.chain.blocks_after(&locator, MAX_BLOCKS_PER_MESSAGE);
+                Ok(vec![Message::Blocks { blocks }])
+            }
+            Message::Blocks { blocks } => {
+                let count = blocks.len();
+                for block in blocks {
+                    match self.accept_block(block, id) {
+                        Ok(()) | Err(NodeError::Chain(ChainError::Known(_))) => {}
+                        Err(error) => {
+                            eprintln!("node: rejected a block from peer {id}: {error}");
+                            return Ok(vec![]);
+                        }
+                    }
+                }
+                if count > 0 {
+                    eprintln!("node: synced {count} blocks from peer {id}");
+                }
+                // a full batch means the peer probably has more
+                if count == MAX_BLOCKS_PER_MESSAGE {
+                    return Ok(vec![self.get_blocks()]);
+                }
+                Ok(vec![])
+            }
+            Message::Block { block } => {
+                let hash = block.hash();
+                match self.accept_block(*block, id) {
+                    Ok(()) => {
+                        eprintln!(
+                            "node: accepted block {} from peer {id}",
+                            util::hash_to_hex(&hash)
+                        );
+                        Ok(vec![])
+                    }
+                    // the peer knows blocks we are missing
+                    Err(NodeError::Chain(ChainError::WrongIv)) => Ok(vec![self.get_blocks()]),
+                    Err(NodeError::Chain(ChainError::Known(_))) => Ok(vec![]),
+                    Err(error) => {
+                        eprintln!("node: rejected a block from peer {id}: {error}");
+                        Ok(vec![])
+                    }
+                }
+            }
+            Message::Transaction { transaction } => {
+                // transactions already known are the common case with gossip, so failures stay quiet
+                let _ = self.accept_transaction(*transaction, id);
+                Ok(vec![])
+            }
+        }
+    }
+}
+
+// what the peer has sent of the handshake so far
+#[derive(Default)]
+struct Handshake {
+    version: bool,
+    verack: bool,
+}
+
+impl Handshake {
+    fn is_done(&self) -> bool {
+        self.version && self.verack
+    }
+
+    // each of version and verack comes once, and before everything else
+    fn check(&mut self, message: &Message) -> Result<(), NodeError> {
+        let seen = match message {
+            Message::Version { .. } => &mut self.version,
+            Message::Verack => &mut self.verack,
+            _ if self.is_done() => return Ok(()),
+            _ => {
+                return Err(NodeError::Protocol(
+                    "message before the handshake".to_string(),
+                ));
+            }
+        };
+        if *seen {
+            return Err(NodeError::Protocol("handshake repeated".to_string()));
+        }
+        *seen = true;
+        Ok(())
+    }
+}
+
+// writes every chain sent to the returned sender to `path`. a chain that is overtaken while an earlier one
+// is still being written is skipped, only the newest one matters.
+fn save_in_background(path: PathBuf) -> watch::Sender<Arc<Vec<u8>>> {
+    let (sender, mut receiver) = watch::channel(Arc::new(vec![]));
+    tokio::spawn(async move {
+        while receiver.changed().await.is_ok() {
+            let data = receiver.borrow_and_update().clone();
+            let path = path.clone();
+            let saved = tokio::task::spawn_blocking(move || Chain::write(&path, &data)).await;
+            if let Err(error) = saved.map_err(io::Error::from).and_then(|saved| saved) {
+                eprintln!("node: failed to save the chain: {error}");
+            }
+        }
+    });
+    sender
+}
+
+async fn send(writer: &mut OwnedWriteHalf, message: &Message) -> Result<(), NodeError> {
+    let mut line = serde_json::to_string(message).map_err(io::Error::from)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::ledger::LedgerMode;
+    use crate::mempool::Order;
+    use crate::reward::Schedule;
+    use crate::util::Hashing;
+
+    const K: u32 = 1;
+    const SCHEDULE: Schedule = Schedule {
+        initial_reward: 1000,
+        halving_interval: 0,
+    };
+
+    // k = 1 takes 16 tries on average, no need for the gpu
+    fn mine(block: &Block) -> Block {
+        (0..)
+            .map(|nonce| block.with_nonce(nonce))
+            .find(Block::meets_target)
+            .unwrap()
+    }
+
+    async fn wait_for(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out");
+    }
+
+    #[tokio::test]
+    async fn saves_accepted_blocks() {
+        let path = std::env::temp_dir().join(format!("node-test-{}.json", std::process::id()));
+        let state = State {
+            chain: Chain::new(LedgerMode::Account, SCHEDULE, Hashing::Sha256),
+            mempool: Mempool::new(Order::Fee, Duration::from_secs(60)),
+        };
+        let config = Config {
+            min_k: K,
+            chain_path: Some(path.clone()),
+        };
+        let node = Node::start("127.0.0.1:0", state, config).await.unwrap();
+        let key = SigningKey::from_bytes(&[7; 32]);
+        for _ in 0..3 {
+            node.submit_block(mine(&node.template(K, 16, &key)))
+                .unwrap();
+        }
+        // the last save wins, however the writes were spaced out
+        wait_for(|| Chain::load(&path).is_ok_and(|chain| chain.tip_hash() == node.tip_hash()))
+            .await;
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn enforces_the_handshake() {
+        let version = Message::Version {
+            version: PROTOCOL_VERSION,
+            height: 0,
+            tip: [0; 8],
+        };
+        let get_blocks = Message::GetBlocks { locator: vec![] };
+
+        let mut handshake = Handshake::default();
+        assert!(handshake.check(&get_blocks).is_err());
+        // in either order
+        let mut handshake = Handshake::default();
+        handshake.check(&Message::Verack).unwrap();
+        assert!(!handshake.is_done());
+        assert!(handshake.check(&get_blocks).is_err());
+        handshake.check(&version).unwrap();
+        assert!(handshake.is_done());
+        handshake.check(&get_blocks).unwrap();
+        assert!(handshake.check(&version).is_err());
+        assert!(handshake.check(&Message::Verack).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use vulkano::device::Device;
+use vulkano::pipeline::cache::{PipelineCache, PipelineCacheCreateInfo};
+
+const CACHE_DIR: &str = "toy_blockchain";
+// VK_PIPELINE_CACHE_HEADER_VERSION_ONE
+const HEADER_VERSION: u32 = 1;
+// header size, version, vendor id, device id and the cache uuid
+const HEADER_SIZE: usize = 4 + 4 + 4 + 4 + 16;
+
+// $XDG_CACHE_HOME, or ~/.cache without it
+fn cache_dir() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(dir.join(CACHE_DIR))
+}
+
+// what the header of a cache from this device and driver looks like
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Header {
+    vendor_id: u32,
+    device_id: u32,
+    uuid: [u8; 16],
+}
+
+impl Header {
+    // the fields are little endian whatever the host is
+    fn parse(data: &[u8]) -> Option<Self> {
+        let word = |index: usize| {
+            let bytes = data.get(index * 4..index * 4 + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        let header_size = word(0)? as usize;
+        if header_size < HEADER_SIZE || header_size > data.len() || word(1)? != HEADER_VERSION {
+            return None;
+        }
+        Some(Header {
+            vendor_id: word(2)?,
+            device_id: word(3)?,
+            uuid: data[16..32].try_into().unwrap(),
+        })
+    }
+}
+
+// the cache data in `file`, if it's intact and was written for `expected`. a file is the sha256 of the
+// data followed by the data.
+fn check(file: &[u8], expected: Header) -> Option<&[u8]> {
+    let (digest, data) = file.split_at_checked(32)?;
+    if digest != Sha256::digest(data).as_slice() || Header::parse(data)? != expected {
+        return None;
+    }
+    Some(data)
+}
+
+// a vulkan pipeline cache that outlives the process, one file per device and driver version
+pub(crate) struct DiskCache {
+    pub(crate) cache: Arc<PipelineCache>,
+    // `None` if there is nowhere to keep it
+    path: Option<PathBuf>,
+}
+
+impl DiskCache {
+    // whatever is wrong with the file on disk, the cache just starts out empty
+    pub(crate) fn open(device: &Arc<Device>) -> Self {
+        let properties = device.physical_device().properties();
+        let expected = Header {
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id,
+            uuid: properties.pipeline_cache_uuid,
+        };
+        let uuid = properties
+            .device_uuid
+            .unwrap_or(properties.pipeline_cache_uuid);
+        let path = cache_dir().map(|dir| {
+            dir.join(format!(
+                "pipelines-{}-{:08x}.bin",
+                hex::encode(uuid),
+                properties.driver_version
+            ))
+        });
+
+        let file = path
+            .as_deref()
+            .and_then(|path| fs::read(path).ok())
+            .unwrap_or_default();
+        let initial_data = match check(&file, expected) {
+            Some(data) => data.to_vec(),
+            None => {
+                if let (false, Some(path)) = (file.is_empty(), &path) {
+                    eprintln!("discarding the pipeline cache {}", path.display());
+                }
+                Vec::new()
+            }
+        };
+        // SAFETY: the data is what `get_data` returned for a device with the same ids and cache uuid, and
+        // the checksum says it wasn't changed since
+        let cache = unsafe {
+            PipelineCache::new(
+                device.clone(),
+                PipelineCacheCreateInfo {
+                    initial_data,
+                    ..Default::default()
+                },
+            )
+        }
+        .expect("failed to create the pipeline cache");
+        Self { cache, path }
+    }
+
+    pub(crate) fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let data = self.cache.get_data().map_err(io::Error::other)?;
+        let mut file = Sha256::digest(&data).to_vec();
+        file.extend(data);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, file)?;
+        fs::rename(tmp_path, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discards_bad_caches() {
+        let header = Header {
+            vendor_id: 0x10de,
+            device_id: 0x2684,
+            uuid: [7; 16],
+        };
+        let mut data = Vec::new();
+        for word in [HEADER_SIZE as u32, HEADER_VERSION, 0x10de, 0x2684] {
+            data.extend(word.to_le_bytes());
+        }
+        data.extend([7; 16]);
+        data.extend(b"driver specific");
+        let mut file = Sha256::digest(&data).to_vec();
+        file.extend(&data);
+
+        assert_eq!(check(&file, header), Some(&data[..]));
+        assert_eq!(check(&file[..20], header), None);
+        let other_driver = Header {
+            uuid: [8; 16],
+            ..header
+        };
+        assert_eq!(check(&file, other_driver), None);
+        let mut corrupt = file.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(check(&corrupt, header), None);
+    }
+}
@@ -0,0 +1,744 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ed25519_dalek::SigningKey;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, watch};
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+
+use crate::block::Block;
+use crate::chain::block_work;
+use crate::miner::GENERATION_STRIDE;
+use crate::miner::Miner;
+use crate::mining::{MAX_BLOCK_TRANSACTIONS, MiningStatus};
+use crate::node::Node;
+use crate::shares::{Payout, ShareLog};
+use crate::util::{self, Hashing};
+use crate::wallet;
+
+// how often the server looks for a new chain tip to build jobs on
+const TIP_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// how long a job's coinbase may go without taking new shares into account
+const JOB_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+// longer lines drop the connection. a job for a block full of transactions is around 100 KB.
+const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+// shares are 256 times easier than blocks
+const SHARE_NIBBLES: u32 = 2;
+const GENERATIONS_PER_JOB: u64 = 64;
+// the shares of about two blocks
+pub const PAYOUT: Payout = Payout::Pplns { window: 512 };
+pub const SHARES_PATH: &str = "pool.json";
+
+// one json object per line, client to server
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Subscribe { worker: String },
+    // asks for a fresh nonce range once the current one is used up
+    GetJob,
+    Submit { job_id: u64, nonce: u64 },
+}
+
+// one json object per line, server to client
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    // the client mines generations `first_generation..first_generation + generations` of `words` for
+    // shares of `k` nibbles. a new job replaces the old one.
+    Job {
+        job_id: u64,
+        words: Vec<u32>,
+        nonce_index: u32,
+        k: u32,
+        // pools from before there was a choice don't send it
+        #[serde(default)]
+        hashing: Hashing,
+        first_generation: u64,
+        generations: u64,
+    },
+    Accepted {
+        job_id: u64,
+        block: bool,
+    },
+    Rejected {
+        job_id: u64,
+        reason: String,
+    },
+}
+
+pub struct Config {
+    // what blocks are mined for
+    pub k: u32,
+    // what shares are mined for, below `k` so that shares come in often enough to measure everyone's work
+    pub share_k: u32,
+    // size of the nonce range handed out at a time
+    pub generations_per_job: u64,
+    pub max_transactions: usize,
+    pub payout: Payout,
+    // where the share log is kept, if anywhere
+    pub shares_path: Option<PathBuf>,
+}
+
+impl Config {
+    // what `pool serve` runs with for blocks at `k`
+    pub fn for_blocks(k: u32) -> Self {
+        Self {
+            k,
+            share_k: share_k(k),
+            generations_per_job: GENERATIONS_PER_JOB,
+            max_transactions: MAX_BLOCK_TRANSACTIONS,
+            payout: PAYOUT,
+            shares_path: Some(SHARES_PATH.into()),
+        }
+    }
+}
+
+// the difficulty of the shares for blocks at `k`
+pub fn share_k(k: u32) -> u32 {
+    k.saturating_sub(SHARE_NIBBLES).max(1)
+}
+
+// the block everyone is currently working on
+struct Template {
+    id: u64,
+    block: Block,
+    words: Vec<u32>,
+    nonce_index: u32,
+    // the next generation nobody has been given yet
+    next_generation: u64,
+    created: Instant,
+}
+
+struct PoolState {
+    template: Template,
+    // (job, nonce) of every share accepted for the current job
+    seen: HashSet<(u64, u64)>,
+    log: ShareLog,
+}
+
+// hands out jobs built on the node's chain tip, checks the shares that come back and submits the ones that
+// are blocks. the coinbase is signed by `key` and split between the workers by the payout scheme. workers
+// are paid to their name, so it should be an address.
+#[derive(Clone)]
+pub struct Pool {
+    node: Node,
+    key: Arc<SigningKey>,
+    config: Arc<Config>,
+    state: Arc<Mutex<PoolState>>,
+    // id of the current job, so connections notice when it changes
+    jobs: watch::Sender<u64>,
+}
+
+impl Pool {
+    fn new(node: Node, key: SigningKey, config: Config) -> io::Result<Self> {
+        let log = match &config.shares_path {
+            Some(path) => ShareLog::open(path)?,
+            None => ShareLog::default(),
+        };
+        let template = Self::template(&node, &key, &config, &log, 0);
+        Ok(Self {
+            node,
+            key: Arc::new(key),
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(PoolState {
+                template,
+                seen: HashSet::new(),
+                log,
+            })),
+            jobs: watch::channel(0).0,
+        })
+    }
+
+    // serves workers on `addr` in the background
+    pub async fn start(
+        addr: impl ToSocketAddrs,
+        node: Node,
+        key: SigningKey,
+        config: Config,
+    ) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let pool = Self::new(node, key, config)?;
+
+        let refreshing = pool.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(TIP_POLL_INTERVAL).await;
+                refreshing.refresh();
+            }
+        });
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(pool.clone().run_worker(stream));
+                    }
+                    Err(error) => eprintln!("pool: failed to accept a worker: {error}"),
+                }
+            }
+        });
+        Ok(local_addr)
+    }
+
+    // the coinbase pays by the shares logged so far. shares that come in later count from the next job on.
+    fn template(
+        node: &Node,
+        key: &SigningKey,
+        config: &Config,
+        log: &ShareLog,
+        id: u64,
+    ) -> Template {
+        let pool = wallet::address(&key.verifying_key());
+        let share_work = block_work(config.share_k);
+        let block = node.template_with(config.k, config.max_transactions, key, |reward| {
+            log.split(config.payout, share_work, reward, &pool)
+        });
+        Template {
+            id,
+            words: block.words(),
+            nonce_index: block.nonce_offset(),
+            block,
+            next_generation: 0,
+            created: Instant::now(),
+        }
+    }
+
+    // starts a new job if the chain tip moved, or if the job is old enough that its payouts are behind
+    fn refresh(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.template.block.iv == self.node.tip_hash()
+            && state.template.created.elapsed() < JOB_REFRESH_INTERVAL
+        {
+            return;
+        }
+        let id = state.template.id + 1;
+        state.template = Self::template(&self.node, &self.key, &self.config, &state.log, id);
+        state.seen.clear();
+        if let Some(path) = &self.config.shares_path
+            && let Err(error) = state.log.save(path)
+        {
+            eprintln!("pool: failed to save the share log: {error}");
+        }
+        drop(state);
+        self.jobs.send_replace(id);
+    }
+
+    // a nonce range of the current job nobody else has
+    fn job(&self) -> (Response, u64, u64) {
+        let mut state = self.state.lock().unwrap();
+        let template = &mut state.template;
+        let first_generation = template.next_generation;
+        template.next_generation += self.config.generations_per_job;
+        let job = Response::Job {
+            job_id: template.id,
+            words: template.words.clone(),
+            nonce_index: template.nonce_index,
+            k: self.config.share_k,
+            hashing: template.block.hashing,
+            first_generation,
+            generations: self.config.generations_per_job,
+        };
+        (job, template.id, first_generation)
+    }
+
+    // `ranges` are the first generations of the ranges the worker was given for job `job_id`
+    fn submit(&self, worker: &str, job_id: u64, nonce: u64, ranges: &[u64]) -> Response {
+        let mut state = self.state.lock().unwrap();
+        let hash = match self.check_share(&mut state, job_id, nonce, ranges) {
+            Ok(hash) => hash,
+            Err(reason) => {
+                state.log.reject(worker);
+                return Response::Rejected {
+                    job_id,
+                    reason: reason.to_string(),
+                };
+            }
+        };
+        let share_work = block_work(self.config.share_k);
+        state
+            .log
+            .accept(worker, share_work, self.config.payout, share_work);
+        if !util::hash_meets_k(&hash, self.config.k) {
+            return Response::Accepted {
+                job_id,
+                block: false,
+            };
+        }
+
+        let block = state.template.block.with_nonce(nonce);
+        drop(state);
+        let outputs = block.transactions[0].outputs.clone();
+        // the share counts either way, but the worker only hears of a block the node took
+        let accepted = match self.node.submit_block(block) {
+            Ok(()) => {
+                eprintln!("pool: {worker} found block {}", util::hash_to_hex(&hash));
+                self.state.lock().unwrap().log.found_block(worker, &outputs);
+                true
+            }
+            Err(error) => {
+                eprintln!("pool: block from {worker} was rejected: {error}");
+                false
+            }
+        };
+        self.refresh();
+        Response::Accepted {
+            job_id,
+            block: accepted,
+        }
+    }
+
+    // the hash of a valid share
+    fn check_share(
+        &self,
+        state: &mut PoolState,
+        job_id: u64,
+        nonce: u64,
+        ranges: &[u64],
+    ) -> Result<[u32; 8], &'static str> {
+        if job_id != state.template.id {
+            return Err("stale job");
+        }
+        // every miner covers exactly one stride per generation, so a range ends where the next one starts
+        let generation = nonce / GENERATION_STRIDE;
+        let in_range = ranges
+            .iter()
+            .any(|&first| (first..first + self.config.generations_per_job).contains(&generation));
+        if !in_range {
+            return Err("nonce outside of the assigned range");
+        }
+        let template = &state.template;
+        let hash =
+            template
+                .block
+                .hashing
+                .hash_with_nonce(&template.words, template.nonce_index, nonce);
+        if !util::hash_meets_k(&hash, self.config.share_k) {
+            return Err("share doesn't meet the share target");
+        }
+        if !state.seen.insert((job_id, nonce)) {
+            return Err("duplicate share");
+        }
+        Ok(hash)
+    }
+
+    fn assign(&self, ranges: &mut (u64, Vec<u64>)) -> Response {
+        let (job, id, first) = self.job();
+        if ranges.0 != id {
+            *ranges = (id, vec![]);
+        }
+        ranges.1.push(first);
+        job
+    }
+
+    async fn run_worker(self, stream: TcpStream) {
+        if let Err(error) = self.serve_worker(stream).await {
+            eprintln!("pool: worker dropped: {error}");
+        }
+    }
+
+    async fn serve_worker(&self, stream: TcpStream) -> io::Result<()> {
+        // shares and jobs are tiny and latency matters
+        stream.set_nodelay(true)?;
+        let (reader, mut writer) = stream.into_split();
+        let mut lines =
+            FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_MESSAGE_LENGTH));
+        let mut jobs = self.jobs.subscribe();
+        let mut worker = None;
+        // the job this worker is on and the first generations of the ranges it got for it. a job change
+        // and a range request can cross, so it may work through more than one.
+        let mut ranges: (u64, Vec<u64>) = (0, vec![]);
+        loop {
+            let reply = tokio::select! {
+                line = lines.next() => {
+                    let Some(line) = line else {
+                        return Ok(());
+                    };
+                    let request: Request = serde_json::from_str(&line.map_err(read_error)?)?;
+                    match (request, &worker) {
+                        (Request::Subscribe { worker: name }, _) => {
+                            eprintln!("pool: {name} subscribed");
+                            worker = Some(name);
+                            jobs.mark_unchanged();
+                            self.assign(&mut ranges)
+                        }
+                        (_, None) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "worker didn't subscribe",
+                            ));
+                        }
+                        (Request::GetJob, Some(_)) => {
+                            self.assign(&mut ranges)
+                        }
+                        (Request::Submit { job_id, nonce }, Some(name)) => {
+                            let assigned = if ranges.0 == job_id { &ranges.1[..] } else { &[] };
+                            self.submit(name, job_id, nonce, assigned)
+                        }
+                    }
+                }
+                changed = jobs.changed(), if worker.is_some() => {
+                    if changed.is_err() {
+                        return Ok(());
+                    }
+                    jobs.mark_unchanged();
+                    self.assign(&mut ranges)
+                }
+            };
+            send(&mut writer, &reply).await?;
+        }
+    }
+}
+
+fn read_error(error: LinesCodecError) -> io::Error {
+    match error {
+        LinesCodecError::Io(error) => error,
+        LinesCodecError::MaxLineLengthExceeded => {
+            io::Error::new(io::ErrorKind::InvalidData, "message too long")
+        }
+    }
+}
+
+async fn send(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    message: &impl Serialize,
+) -> io::Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await
+}
+
+// the part of a job the client hasn't mined yet
+struct Assignment {
+    job_id: u64,
+    words: Vec<u32>,
+    nonce_index: u32,
+    k: u32,
+    hashing: Hashing,
+    generation: u64,
+    end: u64,
+}
+
+// mines the jobs of the pool at `addr` with `miner` until `status` is stopped
+pub async fn run_client(
+    addr: impl ToSocketAddrs,
+    worker: &str,
+    miner: &mut impl Miner,
+    status: &MiningStatus,
+) -> io::Result<()> {
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    let (reader, mut writer) = stream.into_split();
+    send(
+        &mut writer,
+        &Request::Subscribe {
+            worker: worker.to_string(),
+        },
+    )
+    .await?;
+
+    // the miner can't be interrupted mid-invocation, so responses are read in the background and picked
+    // up between invocations
+    let (sender, mut responses) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut lines =
+            FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_MESSAGE_LENGTH));
+        while let Some(Ok(line)) = lines.next().await {
+            match serde_json::from_str::<Response>(&line) {
+                Ok(response) => {
+                    if sender.send(response).is_err() {
+                        break;
+                    }
+                }
+                Err(error) => eprintln!("pool client: bad response: {error}"),
+            }
+        }
+    });
+
+    let mut job: Option<Assignment> = None;
+    while !status.is_stopped() {
+        let response = match &job {
+            Some(_) => responses.try_recv().ok(),
+            // nothing to do until the server says something
+            None => Some(responses.recv().await.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "pool closed the connection",
+                )
+            })?),
+        };
+        match response {
+            Some(Response::Job {
+                job_id,
+                words,
+                nonce_index,
+                k,
+                hashing,
+                first_generation,
+                generations,
+            }) => {
+                job = Some(Assignment {
+                    job_id,
+                    words,
+                    nonce_index,
+                    k,
+                    hashing,
+                    generation: first_generation,
+                    end: first_generation + generations,
+                });
+                continue;
+            }
+            Some(Response::Accepted { block: true, .. }) => eprintln!("pool client: found a block"),
+            Some(Response::Accepted { .. }) => {}
+            Some(Response::Rejected { reason, .. }) => {
+                eprintln!("pool client: share rejected: {reason}")
+            }
+            None => {}
+        }
+
+        let Some(assignment) = &mut job else {
+            continue;
+        };
+        let nonce = miner
+            .search(
+                &assignment.words,
+                assignment.nonce_index,
+                assignment.k,
+                assignment.hashing,
+                assignment.generation,
+            )
+            .await;
+        status.record(assignment.generation, GENERATION_STRIDE);
+        if nonce != 0 {
+            let submit = Request::Submit {
+                job_id: assignment.job_id,
+                nonce,
+            };
+            send(&mut writer, &submit).await?;
+        }
+        assignment.generation += 1;
+        if assignment.generation == assignment.end {
+            job = None;
+            send(&mut writer, &Request::GetJob).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::Chain;
+    use crate::ledger::LedgerMode;
+    use crate::mempool::{Mempool, Order};
+    use crate::miner::CpuMiner;
+    use crate::node;
+    use crate::reward::Schedule;
+    use crate::wallet;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pooled_mining() {
+        let schedule = Schedule {
+            initial_reward: 1000,
+            halving_interval: 0,
+        };
+        let state = node::State {
+            chain: Chain::new(LedgerMode::Account, schedule, Hashing::Sha256),
+            mempool: Mempool::new(Order::Fee, Duration::from_secs(60)),
+        };
+        let config = node::Config {
+            min_k: 2,
+            chain_path: None,
+        };
+        let node = Node::start("127.0.0.1:0", state, config).await.unwrap();
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let config = Config {
+            k: 2,
+            share_k: 1,
+            generations_per_job: 4,
+            max_transactions: 16,
+            payout: Payout::Pplns { window: 100 },
+            shares_path: None,
+        };
+        let pool = Pool::start("127.0.0.1:0", node.clone(), key.clone(), config)
+            .await
+            .unwrap();
+
+        // two workers on the cpu until the pool has found three blocks
+        let status = MiningStatus::new(1);
+        let done = async {
+            for _ in 0..3000 {
+                if node.state().chain.height() >= 3 {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("timed out");
+        };
+        let (mut alice, mut bob) = (CpuMiner, CpuMiner);
+        tokio::select! {
+            result = run_client(pool, "alice", &mut alice, &status) => panic!("client stopped: {result:?}"),
+            result = run_client(pool, "bob", &mut bob, &status) => panic!("client stopped: {result:?}"),
+            () = done => {}
+        }
+        status.stop();
+
+        // the first coinbase was built before any shares came in, the later ones pay the workers
+        let state = node.state();
+        let ledger = &state.chain.ledger;
+        let pool = ledger.balance(&wallet::address(&key.verifying_key()));
+        let workers = ledger.balance("alice") + ledger.balance("bob");
+        assert!(workers > 0);
+        assert_eq!(pool + workers, 3000);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_shares() {
+        let schedule = Schedule {
+            initial_reward: 1000,
+            halving_interval: 0,
+        };
+        let state = node::State {
+            chain: Chain::new(LedgerMode::Account, schedule, Hashing::Sha256),
+            mempool: Mempool::new(Order::Fee, Duration::from_secs(60)),
+        };
+        let config = node::Config {
+            min_k: 8,
+            chain_path: None,
+        };
+        let node = Node::start("127.0.0.1:0", state, config).await.unwrap();
+        let config = Config {
+            k: 8,
+            share_k: 1,
+            generations_per_job: 1,
+            max_transactions: 16,
+            payout: Payout::Proportional,
+            shares_path: None,
+        };
+        let pool = Pool::new(node, SigningKey::from_bytes(&[3; 32]), config).unwrap();
+        let (_, job_id, first) = pool.job();
+        let (words, nonce_index) = {
+            let state = pool.state.lock().unwrap();
+            (state.template.words.clone(), state.template.nonce_index)
+        };
+        let nonce = CpuMiner
+            .search(&words, nonce_index, 1, Hashing::Sha256, first)
+            .await;
+        // a share from the range after this one, which someone else gets
+        let next = CpuMiner
+            .search(&words, nonce_index, 1, Hashing::Sha256, first + 1)
+            .await;
+        let bad = (1..)
+            .find(|&nonce| {
+                !util::hash_meets_k(&util::hash_with_nonce(&words, nonce_index, nonce), 1)
+            })
+            .unwrap();
+
+        let rejected = |response| matches!(response, Response::Rejected { .. });
+        assert!(rejected(pool.submit("alice", job_id + 1, nonce, &[first])));
+        assert!(rejected(pool.submit("alice", job_id, nonce, &[])));
+        assert!(rejected(pool.submit("alice", job_id, bad, &[first])));
+        assert!(rejected(pool.submit("alice", job_id, next, &[first])));
+        assert!(!rejected(pool.submit("alice", job_id, nonce, &[first])));
+        assert!(rejected(pool.submit("alice", job_id, nonce, &[first])));
+        {
+            let stats = &pool.state.lock().unwrap().log.workers["alice"];
+            assert_eq!((stats.accepted, stats.rejected), (1, 5));
+        }
+        // ranges follow each other without overlapping
+        let (_, _, second) = pool.job();
+        assert_eq!(second, first + 1);
+    }
+
+    // a share that meets the pool's k but not the node's is a share, not a block
+    #[tokio::test]
+    async fn reports_the_nodes_verdict() {
+        let schedule = Schedule {
+            initial_reward: 1000,
+            halving_interval: 0,
+        };
+        let state = node::State {
+            chain: Chain::new(LedgerMode::Account, schedule, Hashing::Sha256),
+            mempool: Mempool::new(Order::Fee, Duration::from_secs(60)),
+        };
+        let config = node::Config {
+            min_k: 8,
+            chain_path: None,
+        };
+        let node = Node::start("127.0.0.1:0", state, config).await.unwrap();
+        let config = Config {
+            k: 1,
+            share_k: 1,
+            generations_per_job: 1,
+            max_transactions: 16,
+            payout: Payout::Proportional,
+            shares_path: None,
+        };
+        let pool = Pool::new(node.clone(), SigningKey::from_bytes(&[3; 32]), config).unwrap();
+        let (_, job_id, first) = pool.job();
+        let (words, nonce_index) = {
+            let state = pool.state.lock().unwrap();
+            (state.template.words.clone(), state.template.nonce_index)
+        };
+        let nonce = CpuMiner
+            .search(&words, nonce_index, 1, Hashing::Sha256, first)
+            .await;
+        assert!(matches!(
+            pool.submit("alice", job_id, nonce, &[first]),
+            Response::Accepted { block: false, .. }
+        ));
+        assert_eq!(node.state().chain.height(), 0);
+    }
+
+    #[tokio::test]
+    async fn drops_oversized_lines() {
+        let schedule = Schedule {
+            initial_reward: 1000,
+            halving_interval: 0,
+        };
+        let state = node::State {
+            chain: Chain::new(LedgerMode::Account, schedule, Hashing::Sha256),
+            mempool: Mempool::new(Order::Fee, Duration::from_secs(60)),
+        };
+        let config = node::Config {
+            min_k: 8,
+            chain_path: None,
+        };
+        let node = Node::start("127.0.0.1:0", state, config).await.unwrap();
+        let config = Config {
+            k: 8,
+            share_k: 1,
+            generations_per_job: 1,
+            max_transactions: 16,
+            payout: Payout::Proportional,
+            shares_path: None,
+        };
+        let pool = Pool::start(
+            "127.0.0.1:0",
+            node,
+            SigningKey::from_bytes(&[3; 32]),
+            config,
+        )
+        .await
+        .unwrap();
+
+        // the pool hangs up instead of buffering the line
+        let mut stream = TcpStream::connect(pool).await.unwrap();
+        let line = vec![b' '; MAX_MESSAGE_LENGTH + 1];
+        let _ = stream.write_all(&line).await;
+        let mut reply = vec![];
+        let read = tokio::time::timeout(
+            Duration::from_secs(10),
+            tokio::io::AsyncReadExt::read_to_end(&mut stream, &mut reply),
+        )
+        .await
+        .expect("the pool kept the connection open");
+        assert!(read.is_err() || reply.is_empty());
+    }
+}
@@ -10,3 +10,14 @@ pub struct PushConstants {
     pub(crate) words: DeviceAddress,
     pub(crate) nonce: DeviceAddress,
 }
+
+// the ones of the self-test shader
+#[derive(BufferContents, Copy, Clone)]
+#[repr(C)]
+pub struct SelfTestPushConstants {
+    pub(crate) nonce: u64,
+    pub(crate) word_count: u32,
+    pub(crate) nonce_index: u32,
+    pub(crate) words: DeviceAddress,
+    pub(crate) digests: DeviceAddress,
+}
@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+
+// block reward paid by the coinbase, halved every `halving_interval` blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub initial_reward: u64,
+    pub halving_interval: u64,
+}
+
+impl Schedule {
+    pub fn reward(&self, height: u64) -> u64 {
+        if self.halving_interval == 0 {
+            return self.initial_reward;
+        }
+        let halvings = height / self.halving_interval;
+        self.initial_reward
+            .checked_shr(halvings as u32)
+            .unwrap_or(0)
+    }
+}
@@ -0,0 +1,415 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use axum::Router;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use subtle::ConstantTimeEq;
+use tokio::net::{TcpListener, ToSocketAddrs};
+
+use crate::mining::MiningStatus;
+use crate::node::Node;
+use crate::transaction::Transaction;
+use crate::util;
+
+// standard json-rpc error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// our own
+const NOT_FOUND: i64 = -5;
+const REJECTED: i64 = -25;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    // positional
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+struct Rpc {
+    token: String,
+    node: Node,
+    mining: Arc<MiningStatus>,
+}
+
+// serves json-rpc on `addr` in the background. every request has to carry the token written to
+// `cookie_path` as a bearer token, so only users who can read the file get in.
+pub async fn start(
+    addr: impl ToSocketAddrs,
+    cookie_path: &Path,
+    node: Node,
+    mining: Arc<MiningStatus>,
+) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    let mut token = [0u8; 32];
+    OsRng.fill_bytes(&mut token);
+    let token = hex::encode(token);
+    write_cookie(cookie_path, &token)?;
+
+    let rpc = Arc::new(Rpc {
+        token,
+        node,
+        mining,
+    });
+    let router = Router::new().route("/", post(handle)).with_state(rpc);
+    tokio::spawn(async move {
+        if let Err(error) = axum::serve(listener, router).await {
+            eprintln!("rpc: server stopped: {error}");
+        }
+    });
+    Ok(local_addr)
+}
+
+fn write_cookie(path: &Path, token: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(token.as_bytes())
+}
+
+async fn handle(State(rpc): State<Arc<Rpc>>, headers: HeaderMap, body: String) -> Response {
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        // constant time, so the token can't be guessed byte by byte from how long a rejection takes
+        .is_some_and(|token| token.as_bytes().ct_eq(rpc.token.as_bytes()).into());
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let response = match serde_json::from_str::<Request>(&body) {
+        Ok(request) => {
+            let result = rpc.call(&request.method, &request.params);
+            respond(request.id, result)
+        }
+        Err(error) => respond(
+            Value::Null,
+            Err(RpcError::new(PARSE_ERROR, error.to_string())),
+        ),
+    };
+    axum::Json(response).into_response()
+}
+
+fn respond(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": error.code, "message": error.message },
+        }),
+    }
+}
+
+fn param<'a>(params: &'a [Value], index: usize, name: &str) -> Result<&'a Value, RpcError> {
+    params
+        .get(index)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing parameter `{name}`")))
+}
+
+fn hash_param(params: &[Value], index: usize, name: &str) -> Result<[u32; 8], RpcError> {
+    param(params, index, name)?
+        .as_str()
+        .and_then(util::hex_to_hash)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("`{name}` must be a hex hash")))
+}
+
+impl Rpc {
+    fn call(&self, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+        match method {
+            "getchaintip" => Ok(self.chain_tip()),
+            "getblock" => self.block(params),
+            "gettransaction" => self.transaction(params),
+            "submittransaction" => self.submit_transaction(params),
+            "getbalance" => self.balance(params),
+            "getmininginfo" => Ok(self.mining_info()),
+            "stopmining" => {
+                self.mining.stop();
+                Ok(Value::Null)
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method `{method}`"),
+            )),
+        }
+    }
+
+    fn chain_tip(&self) -> Value {
+        let state = self.node.state();
+        json!({
+            "height": state.chain.height().checked_sub(1),
+            "hash": util::hash_to_hex(&state.chain.tip_hash()),
+            // u128 doesn't fit a json number
+            "work": state.chain.work().to_string(),
+        })
+    }
+
+    // by height or by hash, on the active branch
+    fn block(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let state = self.node.state();
+        let blocks = &state.chain.blocks;
+        let height = match param(params, 0, "block")?.as_u64() {
+            Some(height) => height as usize,
+            None => {
+                let hash = hash_param(params, 0, "block")?;
+                blocks
+                    .iter()
+                    .position(|block| block.hash() == hash)
+                    .ok_or_else(|| RpcError::new(NOT_FOUND, "no such block on the active branch"))?
+            }
+        };
+        let block = blocks
+            .get(height)
+            .ok_or_else(|| RpcError::new(NOT_FOUND, "no block at that height"))?;
+        Ok(json!({
+            "height": height,
+            "hash": util::hash_to_hex(&block.hash()),
+            "block": block,
+        }))
+    }
+
+    // confirmed on the active branch or waiting in the mempool
+    fn transaction(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let txid = hash_param(params, 0, "txid")?;
+        let state = self.node.state();
+        if let Some(transaction) = state.mempool.get(&txid) {
+            return Ok(json!({ "transaction": transaction, "block": null, "confirmations": 0 }));
+        }
+        if state.chain.contains(&txid) {
+            for (height, block) in state.chain.blocks.iter().enumerate() {
+                if let Some(transaction) = block
+                    .transactions
+                    .iter()
+                    .find(|transaction| transaction.hash() == txid)
+                {
+                    return Ok(json!({
+                        "transaction": transaction,
+                        "block": util::hash_to_hex(&block.hash()),
+                        "confirmations": state.chain.height() - height as u64,
+                    }));
+                }
+            }
+        }
+        Err(RpcError::new(NOT_FOUND, "no such transaction"))
+    }
+
+    fn submit_transaction(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let transaction: Transaction =
+            serde_json::from_value(param(params, 0, "transaction")?.clone())
+                .map_err(|error| RpcError::new(INVALID_PARAMS, error.to_string()))?;
+        let txid = self
+            .node
+            .submit_transaction(transaction)
+            .map_err(|error| RpcError::new(REJECTED, error.to_string()))?;
+        Ok(json!(util::hash_to_hex(&txid)))
+    }
+
+    fn balance(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let address = param(params, 0, "address")?
+            .as_str()
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "`address` must be a string"))?;
+        Ok(json!(self.node.state().chain.ledger.balance(address)))
+    }
+
+    fn mining_info(&self) -> Value {
+        json!({
+            "mining": !self.mining.is_stopped(),
+            "k": self.mining.k(),
+            "generation": self.mining.generation(),
+            "hashes": self.mining.hashes(),
+            "hashrate": self.mining.hashrate(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+    use crate::block::Block;
+    use crate::chain::Chain;
+    use crate::ledger::{LedgerMode, Output};
+    use crate::mempool::{Mempool, Order};
+    use crate::node::{Config, State};
+    use crate::reward::Schedule;
+    use crate::util::Hashing;
+
+    // a bare http/1.1 exchange, returning the status line and the body
+    async fn post(addr: SocketAddr, token: &str, body: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {token}\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+
+    // a node on a fresh account chain with the rpc server in front of it, and the token
+    async fn start_rpc() -> (Node, Arc<MiningStatus>, SocketAddr, String) {
+        let state = State {
+            chain: Chain::new(
+                LedgerMode::Account,
+                Schedule {
+                    initial_reward: 1000,
+                    halving_interval: 0,
+                },
+                Hashing::Sha256,
+            ),
+            mempool: Mempool::new(Order::Fee, Duration::from_secs(60)),
+        };
+        let config = Config {
+            min_k: 1,
+            chain_path: None,
+        };
+        let node = Node::start("127.0.0.1:0", state, config).await.unwrap();
+        let mining = Arc::new(MiningStatus::new(1));
+        let cookie = std::env::temp_dir().join(format!(
+            "rpc-test-{}-{}.cookie",
+            std::process::id(),
+            node.local_addr().port()
+        ));
+        let addr = start("127.0.0.1:0", &cookie, node.clone(), mining.clone())
+            .await
+            .unwrap();
+        let token = fs::read_to_string(&cookie).unwrap();
+        fs::remove_file(&cookie).unwrap();
+        (node, mining, addr, token)
+    }
+
+    async fn call(addr: SocketAddr, token: &str, method: &str, params: Value) -> Value {
+        let request = json!({ "id": 1, "method": method, "params": params });
+        let (_, body) = post(addr, token, &request.to_string()).await;
+        serde_json::from_str(&body).unwrap()
+    }
+
+    // k = 1 takes 16 tries on average, no need for the gpu
+    fn mine(block: &Block) -> Block {
+        (0..)
+            .map(|nonce| block.with_nonce(nonce))
+            .find(Block::meets_target)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn authenticated_calls() {
+        let (_, mining, addr, token) = start_rpc().await;
+
+        let (status, _) = post(addr, "wrong", r#"{"id":1,"method":"getchaintip"}"#).await;
+        assert!(status.contains("401"));
+        let (status, _) = post(addr, &token[1..], r#"{"id":1,"method":"getchaintip"}"#).await;
+        assert!(status.contains("401"));
+
+        let (_, body) = post(
+            addr,
+            &token,
+            r#"{"id":1,"method":"getbalance","params":["Alice"]}"#,
+        )
+        .await;
+        let response: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"], 0);
+
+        let (_, body) = post(addr, &token, r#"{"id":2,"method":"nope"}"#).await;
+        let response: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+
+        post(addr, &token, r#"{"id":3,"method":"stopmining"}"#).await;
+        assert!(mining.is_stopped());
+    }
+
+    #[tokio::test]
+    async fn blocks_and_transactions() {
+        let (node, _, addr, token) = start_rpc().await;
+        let key = SigningKey::from_bytes(&[5; 32]);
+        node.submit_block(mine(&node.template(1, 16, &key)))
+            .unwrap();
+
+        // submitted, then waiting in the mempool
+        let outputs = vec![Output {
+            recipient: "Carol".to_string(),
+            value: 50,
+        }];
+        let payment = Transaction::payment(1, vec![], outputs, 2, &key);
+        let txid = util::hash_to_hex(&payment.hash());
+        let response = call(addr, &token, "submittransaction", json!([payment])).await;
+        assert_eq!(response["result"], txid);
+        let response = call(addr, &token, "submittransaction", json!([payment])).await;
+        assert_eq!(response["error"]["code"], REJECTED);
+        let response = call(addr, &token, "submittransaction", json!([{ "fee": 1 }])).await;
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        let response = call(addr, &token, "gettransaction", json!([txid])).await;
+        assert_eq!(response["result"]["confirmations"], 0);
+        assert_eq!(response["result"]["block"], Value::Null);
+
+        // mined into the second block
+        node.submit_block(mine(&node.template(1, 16, &key)))
+            .unwrap();
+        let tip = util::hash_to_hex(&node.tip_hash());
+        let response = call(addr, &token, "gettransaction", json!([txid])).await;
+        assert_eq!(response["result"]["confirmations"], 1);
+        assert_eq!(response["result"]["block"], tip);
+        assert_eq!(response["result"]["transaction"]["fee"], 2);
+
+        let response = call(addr, &token, "getblock", json!([1])).await;
+        assert_eq!(response["result"]["hash"], tip);
+        assert_eq!(response["result"]["block"]["transactions"][1]["fee"], 2);
+        let response = call(addr, &token, "getblock", json!([tip])).await;
+        assert_eq!(response["result"]["height"], 1);
+
+        // missing and malformed
+        let response = call(addr, &token, "getblock", json!([2])).await;
+        assert_eq!(response["error"]["code"], NOT_FOUND);
+        // a block hash is no txid
+        let response = call(addr, &token, "gettransaction", json!([tip])).await;
+        assert_eq!(response["error"]["code"], NOT_FOUND);
+        let signed = format!("+{}", &tip[1..]);
+        let response = call(addr, &token, "getblock", json!([signed])).await;
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        let response = call(addr, &token, "gettransaction", json!([])).await;
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+    }
+}
@@ -0,0 +1,319 @@
+// checks that the shader hashes like util::sha256 before a context mines anything
+
+use std::sync::Arc;
+
+use vulkano::buffer::{Buffer, BufferCreateInfo, BufferUsage};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage};
+use vulkano::memory::allocator::{AllocationCreateInfo, MemoryTypeFilter};
+use vulkano::pipeline::layout::{PipelineLayoutCreateInfo, PushConstantRange};
+use vulkano::pipeline::{ComputePipeline, PipelineLayout};
+use vulkano::shader::ShaderStages;
+use vulkano::sync::{self, GpuFuture};
+
+use crate::context::{Context, JobShape};
+use crate::miner::{BackendError, GENERATION_STRIDE, WORKGROUP_SIZE};
+use crate::push_constants::{PushConstants, SelfTestPushConstants};
+use crate::shader;
+use crate::util::{self, Hashing};
+
+// has to match numthreads in the self-test shader
+const INVOCATIONS: u32 = 64;
+
+// what a block looks like to the shader: two blocks of words with the nonce somewhere in the middle. the
+// nonces start just below 2^32, so the high word changes halfway through the invocations.
+const BLOCK_NONCE_INDEX: u32 = 9;
+const BLOCK_NONCE: u64 = 0xffff_ffe0;
+
+fn block_words() -> Vec<u32> {
+    util::to_words(&util::pad(&(0..100).collect::<Vec<u8>>()))
+}
+
+// the mining pipelines mine these: even a single work group can't miss k = 2 for a few generations in a row
+const MINE_TEST_KS: [u32; 2] = [1, 2];
+const MINE_TEST_GENERATIONS: u64 = 4;
+// the first generation whose nonces don't fit in the low word
+const HIGH_GENERATION: u64 = (1 << 32) / GENERATION_STRIDE;
+
+// the block from above, the same words with the nonce across the first block boundary, and four blocks with
+// the nonce in the last one
+fn mine_test_shapes() -> [(Vec<u32>, u32); 3] {
+    let long = util::to_words(&util::pad(&(0..200).collect::<Vec<u8>>()));
+    [
+        (block_words(), BLOCK_NONCE_INDEX),
+        (block_words(), 15),
+        (long, 50),
+    ]
+}
+
+// the pipeline running the self-test shader, with the layout of its push constants
+pub(crate) struct DigestPipeline {
+    pipeline: Arc<ComputePipeline>,
+    layout: Arc<PipelineLayout>,
+}
+
+impl Context {
+    // an error names the first input the gpu got wrong
+    pub(crate) fn self_test(&mut self) -> Result<(), BackendError> {
+        let pipeline = self.digest_pipeline();
+
+        for (message, digest) in util::NIST_VECTORS {
+            let expected = util::hex_to_hash(digest).unwrap();
+            let words = util::to_words(&util::pad(message.as_bytes()));
+            let cpu = util::sha256(&words);
+            if cpu != expected {
+                return Err(mismatch(
+                    &format!("{message:?} on the cpu"),
+                    &expected,
+                    &cpu,
+                ));
+            }
+            // past the words, so nothing is replaced
+            let nonce_index = words.len() as u32;
+            let digests = self.digests(&pipeline, &words, nonce_index, 0);
+            if let Some(gpu) = digests.iter().find(|gpu| **gpu != expected) {
+                return Err(mismatch(&format!("{message:?}"), &expected, gpu));
+            }
+        }
+
+        let words = block_words();
+        let digests = self.digests(&pipeline, &words, BLOCK_NONCE_INDEX, BLOCK_NONCE);
+        for (nonce, gpu) in (BLOCK_NONCE..).zip(&digests) {
+            let expected = util::hash_with_nonce(&words, BLOCK_NONCE_INDEX, nonce);
+            if *gpu != expected {
+                return Err(mismatch(&format!("a block, nonce {nonce}"), &expected, gpu));
+            }
+        }
+        // the digests come from their own shader, so the mining pipelines get checked too
+        self.mine_test()
+    }
+
+    fn digest_pipeline(&self) -> DigestPipeline {
+        let entry_point = shader::load_self_test(self.device.clone())
+            .expect("failed to load the self-test shader")
+            .entry_point("main")
+            .unwrap();
+        let layout = PipelineLayout::new(
+            self.device.clone(),
+            PipelineLayoutCreateInfo {
+                push_constant_ranges: vec![PushConstantRange {
+                    stages: ShaderStages::COMPUTE,
+                    offset: 0,
+                    size: size_of::<SelfTestPushConstants>() as u32,
+                }],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let pipeline =
+            Self::create_pipeline(&self.device, &layout, &self.pipeline_cache, entry_point);
+        DigestPipeline { pipeline, layout }
+    }
+
+    // the digests of `words` with the nonces from `nonce` on, one per invocation
+    fn digests(
+        &self,
+        pipeline: &DigestPipeline,
+        words: &[u32],
+        nonce_index: u32,
+        nonce: u64,
+    ) -> Vec<[u32; 8]> {
+        let words_buffer = Self::create_words_buffer(self.allocator.clone(), words);
+        let digests_buffer = Buffer::from_iter(
+            self.allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::SHADER_DEVICE_ADDRESS,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            (0..INVOCATIONS * 8).map(|_| 0u32),
+        )
+        .expect("Failed to create the digests buffer!");
+        let push_constants = SelfTestPushConstants {
+            nonce,
+            word_count: words.len() as u32,
+            nonce_index,
+            words: words_buffer.device_address().unwrap().into(),
+            digests: digests_buffer.device_address().unwrap().into(),
+        };
+
+        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+            self.command_buffer_allocator.clone(),
+            self.queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        unsafe {
+            command_buffer_builder
+                .bind_pipeline_compute(pipeline.pipeline.clone())
+                .unwrap()
+                .push_constants(pipeline.layout.clone(), 0, push_constants)
+                .unwrap()
+                .dispatch([1, 1, 1])
+                .unwrap();
+        }
+        let command_buffer = command_buffer_builder.build().unwrap();
+        sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), command_buffer)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        digests_buffer
+            .read()
+            .unwrap()
+            .chunks_exact(8)
+            .map(|digest| digest.try_into().unwrap())
+            .collect()
+    }
+
+    // mines every shape at a few ks in every hashing, on the pipelines `search` would use, starting at
+    // generation 0 and again where the nonces need the high word. each nonce has to come from the
+    // generations that were dispatched and pass on the cpu.
+    fn mine_test(&mut self) -> Result<(), BackendError> {
+        let dispatched = self.work_group_counts[0] as u64 * WORKGROUP_SIZE;
+        for (words, nonce_index) in mine_test_shapes() {
+            self.update_words(&words);
+            for hashing in [Hashing::Sha256, Hashing::Sha256d] {
+                for k in MINE_TEST_KS {
+                    let shape = JobShape {
+                        block_count: words.len() as u32 / 16,
+                        nonce_index,
+                        k,
+                        hashing,
+                        early_exit: self.early_exit,
+                    };
+                    for first in [0, HIGH_GENERATION] {
+                        self.mine_shape(&words, shape, first, dispatched)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn mine_shape(
+        &mut self,
+        words: &[u32],
+        shape: JobShape,
+        first: u64,
+        dispatched: u64,
+    ) -> Result<(), BackendError> {
+        let JobShape {
+            nonce_index,
+            k,
+            hashing,
+            ..
+        } = shape;
+        let pipeline = self.pipeline_for(shape);
+        let shape = format!(
+            "{} words, nonce at word {nonce_index}, k = {k}, {hashing}",
+            words.len()
+        );
+        for generation in first..first + MINE_TEST_GENERATIONS {
+            let push_constants = PushConstants {
+                generation,
+                word_count: words.len() as u32,
+                nonce_index,
+                words: self.words_address().into(),
+                nonce: self.nonce_address().into(),
+                k,
+            };
+            let nonce = self.invoke(pipeline.clone(), &push_constants);
+            if nonce == 0 {
+                continue;
+            }
+            let start = generation * GENERATION_STRIDE;
+            if !(start..start + dispatched).contains(&nonce) {
+                return Err(BackendError::SelfTest(format!(
+                    "the gpu mined nonce {nonce} in generation {generation} ({shape}), which it never tried"
+                )));
+            }
+            let hash = hashing.hash_with_nonce(words, nonce_index, nonce);
+            if !util::hash_meets_k(&hash, k) {
+                return Err(BackendError::SelfTest(format!(
+                    "the gpu mined nonce {nonce} ({shape}), but the cpu hashes it to {}",
+                    util::hash_to_hex(&hash)
+                )));
+            }
+            return Ok(());
+        }
+        Err(BackendError::SelfTest(format!(
+            "the gpu found no nonce in generation {first} and the {} after it ({shape})",
+            MINE_TEST_GENERATIONS - 1
+        )))
+    }
+}
+
+fn mismatch(input: &str, expected: &[u32; 8], got: &[u32; 8]) -> BackendError {
+    BackendError::SelfTest(format!(
+        "{input} hashed to {}, expected {}",
+        util::hash_to_hex(got),
+        util::hash_to_hex(expected)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, RngCore, SeedableRng};
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::miner::Miner;
+
+    fn reference(data: &[u8]) -> [u32; 8] {
+        util::hex_to_hash(&hex::encode(Sha256::digest(data))).unwrap()
+    }
+
+    // skipped without a vulkan device, lavapipe is enough
+    #[tokio::test]
+    async fn gpu_matches_sha2() {
+        let Some(mut context) = Context::for_tests(&block_words()) else {
+            eprintln!("no vulkan device, skipping");
+            return;
+        };
+        let mut rng = StdRng::seed_from_u64(64);
+        let random_bytes = |rng: &mut StdRng, len| {
+            let mut data = vec![0; len];
+            rng.fill_bytes(&mut data);
+            data
+        };
+
+        let pipeline = context.digest_pipeline();
+        let long: Vec<usize> = (0..20).map(|_| rng.gen_range(131..1024)).collect();
+        for len in (0..=130).chain(long) {
+            let data = random_bytes(&mut rng, len);
+            let words = util::to_words(&util::pad(&data));
+            let digests = context.digests(&pipeline, &words, words.len() as u32, 0);
+            assert!(
+                digests.iter().all(|gpu| *gpu == reference(&data)),
+                "{len} bytes"
+            );
+        }
+
+        for _ in 0..8 {
+            let len = rng.gen_range(8..300);
+            let mut data = random_bytes(&mut rng, len);
+            let words = util::to_words(&util::pad(&data));
+            let nonce_index = rng.gen_range(0..len / 4 - 1);
+            let nonce = context
+                .search(&words, nonce_index as u32, 1, Hashing::Sha256, 0)
+                .await;
+            data[nonce_index * 4..nonce_index * 4 + 8].copy_from_slice(&nonce.to_be_bytes());
+            assert!(util::hash_meets_k(&reference(&data), 1), "nonce {nonce}");
+
+            let nonce = context
+                .search(&words, nonce_index as u32, 1, Hashing::Sha256d, 0)
+                .await;
+            data[nonce_index * 4..nonce_index * 4 + 8].copy_from_slice(&nonce.to_be_bytes());
+            let double = reference(&Sha256::digest(&data));
+            assert!(util::hash_meets_k(&double, 1), "sha256d nonce {nonce}");
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// util::compress on the intel sha extensions. the round structure follows intel's reference code, minus
+// the byte swap, since the words are already big endian.
+
+use std::arch::x86_64::*;
+
+use crate::util;
+
+pub(crate) fn available() -> bool {
+    is_x86_feature_detected!("sha")
+        && is_x86_feature_detected!("sse2")
+        && is_x86_feature_detected!("ssse3")
+        && is_x86_feature_detected!("sse4.1")
+}
+
+// SAFETY: callers check `available` first
+#[inline]
+#[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
+pub(crate) unsafe fn compress(state: &mut [u32; 8], block: &[u32; 16]) {
+    unsafe {
+        // the rounds work on abef and cdgh
+        let dcba = _mm_loadu_si128(state.as_ptr().cast());
+        let hgfe = _mm_loadu_si128(state.as_ptr().add(4).cast());
+        let cdab = _mm_shuffle_epi32(dcba, 0xb1);
+        let efgh = _mm_shuffle_epi32(hgfe, 0x1b);
+        let mut abef = _mm_alignr_epi8(cdab, efgh, 8);
+        let mut cdgh = _mm_blend_epi16(efgh, cdab, 0xf0);
+        let (abef_save, cdgh_save) = (abef, cdgh);
+
+        let mut messages =
+            [0, 4, 8, 12].map(|word| _mm_loadu_si128(block.as_ptr().add(word).cast()));
+        // four rounds at a time, scheduling the message words of later ones along the way
+        for quad in 0..16 {
+            let message = _mm_add_epi32(
+                messages[quad % 4],
+                _mm_loadu_si128(util::K.as_ptr().add(quad * 4).cast()),
+            );
+            cdgh = _mm_sha256rnds2_epu32(cdgh, abef, message);
+            if (3..15).contains(&quad) {
+                let next = (quad + 1) % 4;
+                let shifted = _mm_alignr_epi8(messages[quad % 4], messages[(quad + 3) % 4], 4);
+                messages[next] = _mm_sha256msg2_epu32(
+                    _mm_add_epi32(messages[next], shifted),
+                    messages[quad % 4],
+                );
+            }
+            abef = _mm_sha256rnds2_epu32(abef, cdgh, _mm_shuffle_epi32(message, 0x0e));
+            if (1..13).contains(&quad) {
+                let previous = (quad + 3) % 4;
+                messages[previous] = _mm_sha256msg1_epu32(messages[previous], messages[quad % 4]);
+            }
+        }
+
+        abef = _mm_add_epi32(abef, abef_save);
+        cdgh = _mm_add_epi32(cdgh, cdgh_save);
+        let feba = _mm_shuffle_epi32(abef, 0x1b);
+        let dchg = _mm_shuffle_epi32(cdgh, 0xb1);
+        let dcba = _mm_blend_epi16(feba, dchg, 0xf0);
+        let hgfe = _mm_alignr_epi8(dchg, feba, 8);
+        _mm_storeu_si128(state.as_mut_ptr().cast(), dcba);
+        _mm_storeu_si128(state.as_mut_ptr().add(4).cast(), hgfe);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn matches_the_portable_compression() {
+        if !available() {
+            eprintln!("no sha extensions, skipping");
+            return;
+        }
+        let mut rng = StdRng::seed_from_u64(48);
+        for _ in 0..1000 {
+            let state: [u32; 8] = rng.r#gen();
+            let block: [u32; 16] = rng.r#gen();
+            let mut portable = state;
+            util::compress_portable(&mut portable, &block);
+            let mut hardware = state;
+            unsafe { compress(&mut hardware, &block) };
+            assert_eq!(hardware, portable, "{state:08x?} {block:08x?}");
+        }
+    }
+}
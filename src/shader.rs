@@ -1,3 +1,25 @@
-vulkano_shaders::shader! {
-    bytes: "shaders/crypto.spirv"
+use std::sync::Arc;
+
+use vulkano::device::Device;
+use vulkano::shader::{ShaderModule, ShaderModuleCreateInfo, spirv};
+use vulkano::{Validated, VulkanError};
+
+// what build.rs compiled from the slang sources, or the committed copy if there is no slangc
+const SPECIALIZED_SPIRV: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/specialized.spirv"));
+const SELF_TEST_SPIRV: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/self_test.spirv"));
+
+// the shader that takes the job shape as specialization constants
+pub fn load_specialized(device: Arc<Device>) -> Result<Arc<ShaderModule>, Validated<VulkanError>> {
+    module(device, SPECIALIZED_SPIRV)
+}
+
+// the shader that writes out whole digests for the self-test
+pub fn load_self_test(device: Arc<Device>) -> Result<Arc<ShaderModule>, Validated<VulkanError>> {
+    module(device, SELF_TEST_SPIRV)
+}
+
+fn module(device: Arc<Device>, bytes: &[u8]) -> Result<Arc<ShaderModule>, Validated<VulkanError>> {
+    let words = spirv::bytes_to_words(bytes).expect("spir-v is made of whole words");
+    // SAFETY: it's our own shader, the same trust the shader! macro would put in it
+    unsafe { ShaderModule::new(device, ShaderModuleCreateInfo::new(&words)) }
 }
@@ -0,0 +1,289 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::ledger::Output;
+
+// how the reward of a block found by the pool is split between the workers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Payout {
+    // by the shares of the current round, the one since the pool last found a block
+    Proportional,
+    // by the last `window` shares at the current share difficulty, whatever round they are from, so
+    // hopping in and out of the pool doesn't pay
+    Pplns { window: u64 },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Share {
+    worker: String,
+    work: u128,
+    round: u64,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct WorkerStats {
+    pub(crate) accepted: u64,
+    pub(crate) rejected: u64,
+    // accepted shares weighted by their difficulty
+    pub(crate) work: u128,
+    pub(crate) blocks: u64,
+    // what coinbases of the pool's blocks paid the worker so far
+    pub(crate) paid: u64,
+    pub(crate) last_share: Option<DateTime<Utc>>,
+}
+
+// the pool's bookkeeping. persisted, so payouts survive a restart.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ShareLog {
+    // oldest first, trimmed to what payouts can still look at
+    shares: VecDeque<Share>,
+    round: u64,
+    pub(crate) workers: BTreeMap<String, WorkerStats>,
+}
+
+impl ShareLog {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    // starts an empty log if there is none at `path` yet
+    pub fn open(path: &Path) -> io::Result<Self> {
+        match Self::load(path) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            result => result,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let data = serde_json::to_vec(self)?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(tmp_path, path)
+    }
+
+    // `work` is what the share is worth, `share_work` what a share at the current share difficulty is
+    pub fn accept(&mut self, worker: &str, work: u128, payout: Payout, share_work: u128) {
+        self.shares.push_back(Share {
+            worker: worker.to_string(),
+            work,
+            round: self.round,
+        });
+        let stats = self.workers.entry(worker.to_string()).or_default();
+        stats.accepted += 1;
+        stats.work = stats.work.saturating_add(work);
+        stats.last_share = Some(Utc::now());
+        self.trim(payout, share_work);
+    }
+
+    pub fn reject(&mut self, worker: &str) {
+        self.workers.entry(worker.to_string()).or_default().rejected += 1;
+    }
+
+    // `worker` found a block whose coinbase pays `outputs`. ends the round.
+    pub fn found_block(&mut self, worker: &str, outputs: &[Output]) {
+        self.workers.entry(worker.to_string()).or_default().blocks += 1;
+        for output in outputs {
+            if let Some(stats) = self.workers.get_mut(&output.recipient) {
+                stats.paid += output.value;
+            }
+        }
+        self.round += 1;
+    }
+
+    // drops the shares no payout can count anymore
+    fn trim(&mut self, payout: Payout, share_work: u128) {
+        match payout {
+            Payout::Proportional => {
+                while self
+                    .shares
+                    .front()
+                    .is_some_and(|share| share.round < self.round)
+                {
+                    self.shares.pop_front();
+                }
+            }
+            Payout::Pplns { window } => {
+                let window = share_work.saturating_mul(window as u128);
+                let mut total = self
+                    .shares
+                    .iter()
+                    .fold(0u128, |total, share| total.saturating_add(share.work));
+                while let Some(front) = self.shares.front() {
+                    if total - front.work < window {
+                        break;
+                    }
+                    total -= front.work;
+                    self.shares.pop_front();
+                }
+            }
+        }
+    }
+
+    // the work each worker is paid for by `payout`
+    fn weights(&self, payout: Payout, share_work: u128) -> BTreeMap<&str, u128> {
+        let mut weights: BTreeMap<&str, u128> = BTreeMap::new();
+        match payout {
+            Payout::Proportional => {
+                for share in self.shares.iter().filter(|share| share.round == self.round) {
+                    let weight = weights.entry(&share.worker).or_default();
+                    *weight = weight.saturating_add(share.work);
+                }
+            }
+            Payout::Pplns { window } => {
+                // newest first, the oldest share counted only partially if it straddles the window
+                let mut remaining = share_work.saturating_mul(window as u128);
+                for share in self.shares.iter().rev() {
+                    if remaining == 0 {
+                        break;
+                    }
+                    let work = share.work.min(remaining);
+                    remaining -= work;
+                    *weights.entry(&share.worker).or_default() += work;
+                }
+            }
+        }
+        weights
+    }
+
+    // coinbase outputs splitting `reward` by `payout`. the worker name is the address it's paid to.
+    // rounding dust and everything nobody has shares for goes to `pool`.
+    pub fn split(&self, payout: Payout, share_work: u128, reward: u64, pool: &str) -> Vec<Output> {
+        let weights = self.weights(payout, share_work);
+        let total = weights
+            .values()
+            .fold(0u128, |total, weight| total.saturating_add(*weight));
+        // keep reward * weight within a u128
+        let shift = (128 - total.leading_zeros()).saturating_sub(64);
+        let total = total >> shift;
+        let mut outputs = vec![];
+        let mut paid = 0;
+        for (worker, weight) in weights {
+            let value = (reward as u128 * (weight >> shift))
+                .checked_div(total)
+                .unwrap_or_default() as u64;
+            if value > 0 {
+                outputs.push(Output {
+                    recipient: worker.to_string(),
+                    value,
+                });
+                paid += value;
+            }
+        }
+        if reward > paid {
+            outputs.push(Output {
+                recipient: pool.to_string(),
+                value: reward - paid,
+            });
+        }
+        outputs
+    }
+
+    // one line per worker
+    pub fn report(&self, payout: Payout, share_work: u128) -> String {
+        let weights = self.weights(payout, share_work);
+        let total = weights
+            .values()
+            .fold(0u128, |total, weight| total.saturating_add(*weight));
+        let mut report = format!(
+            "{:<40} {:>9} {:>9} {:>12} {:>7} {:>10} {:>11}  {}\n",
+            "worker", "accepted", "rejected", "work", "blocks", "paid", "next block", "last share"
+        );
+        for (worker, stats) in &self.workers {
+            let weight = weights.get(worker.as_str()).copied().unwrap_or_default();
+            let next = if total > 0 {
+                weight as f64 / total as f64 * 100.0
+            } else {
+                0.0
+            };
+            let last_share = stats
+                .last_share
+                .map(|time| time.to_rfc3339())
+                .unwrap_or_else(|| "-".to_string());
+            let _ = writeln!(
+                report,
+                "{worker:<40} {:>9} {:>9} {:>12} {:>7} {:>10} {:>10.2}%  {last_share}",
+                stats.accepted, stats.rejected, stats.work, stats.blocks, stats.paid, next
+            );
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHARE: u128 = 16;
+
+    fn values(outputs: &[Output]) -> Vec<(&str, u64)> {
+        outputs
+            .iter()
+            .map(|output| (output.recipient.as_str(), output.value))
+            .collect()
+    }
+
+    #[test]
+    fn proportional_pays_the_current_round() {
+        let payout = Payout::Proportional;
+        let mut log = ShareLog::default();
+        log.accept("alice", SHARE, payout, SHARE);
+        log.found_block("alice", &[]);
+        log.accept("alice", SHARE, payout, SHARE);
+        log.accept("bob", SHARE, payout, SHARE);
+        log.accept("bob", SHARE, payout, SHARE);
+        // the first round is gone, 1:2 with a unit of dust for the pool
+        assert_eq!(
+            values(&log.split(payout, SHARE, 100, "pool")),
+            [("alice", 33), ("bob", 66), ("pool", 1)]
+        );
+    }
+
+    #[test]
+    fn pplns_pays_the_last_window() {
+        let payout = Payout::Pplns { window: 4 };
+        let mut log = ShareLog::default();
+        for _ in 0..4 {
+            log.accept("alice", SHARE, payout, SHARE);
+        }
+        log.found_block("alice", &[]);
+        log.accept("bob", SHARE, payout, SHARE);
+        // a share worth two covers half of the window on its own
+        log.accept("carol", 2 * SHARE, payout, SHARE);
+        assert_eq!(
+            values(&log.split(payout, SHARE, 100, "pool")),
+            [("alice", 25), ("bob", 25), ("carol", 50)]
+        );
+        assert_eq!(log.shares.len(), 3);
+    }
+
+    #[test]
+    fn no_shares_pay_the_pool() {
+        let log = ShareLog::default();
+        assert_eq!(
+            values(&log.split(Payout::Pplns { window: 4 }, SHARE, 100, "pool")),
+            [("pool", 100)]
+        );
+    }
+
+    #[test]
+    fn stats_follow_blocks() {
+        let payout = Payout::Proportional;
+        let mut log = ShareLog::default();
+        log.accept("alice", SHARE, payout, SHARE);
+        log.reject("alice");
+        let outputs = log.split(payout, SHARE, 100, "pool");
+        log.found_block("alice", &outputs);
+        let stats = &log.workers["alice"];
+        assert_eq!(
+            (stats.accepted, stats.rejected, stats.blocks, stats.paid),
+            (1, 1, 1, 100)
+        );
+        assert!(log.report(payout, SHARE).contains("alice"));
+    }
+}
@@ -0,0 +1,697 @@
+// the cpu mining kernel. it hashes as many nonces at once as the cpu has simd lanes, starting every one of
+// them from the midstate after the blocks in front of the nonce, which are the same for all of them.
+
+use std::ops::Range;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use crate::util::{self, Hashing};
+
+// a way to hash nonces on the cpu, several at once where there are simd lanes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    // 16 lanes
+    Avx512,
+    // one nonce at a time, but in hardware
+    ShaNi,
+    // 8 lanes
+    Avx2,
+    // 4 lanes, every x86_64 cpu has it
+    Sse2,
+    // 4 lanes
+    Neon,
+    // one nonce at a time, still from the midstate
+    Scalar,
+}
+
+impl Kernel {
+    // the fastest one this cpu can run. which that is depends on more than the features, sha-ni beats avx2
+    // on some cpus and loses on others, so they race once on a small job.
+    pub fn detect() -> Self {
+        static FASTEST: OnceLock<Kernel> = OnceLock::new();
+        *FASTEST.get_or_init(|| {
+            let job = Job::new(&[0; 32], 20, Hashing::Sha256);
+            Self::available()
+                .into_iter()
+                .min_by_key(|&kernel| {
+                    (0..3)
+                        .map(|_| {
+                            let started = Instant::now();
+                            job.search(kernel, 0..4096, 64);
+                            started.elapsed()
+                        })
+                        .min()
+                })
+                .unwrap()
+        })
+    }
+
+    // the ones this cpu can run. `Scalar` always is one.
+    pub fn available() -> Vec<Self> {
+        let mut kernels = Vec::new();
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx512f") {
+                kernels.push(Kernel::Avx512);
+            }
+            if crate::sha_ni::available() {
+                kernels.push(Kernel::ShaNi);
+            }
+            if is_x86_feature_detected!("avx2") {
+                kernels.push(Kernel::Avx2);
+            }
+            kernels.push(Kernel::Sse2);
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            kernels.push(Kernel::Neon);
+        }
+        kernels.push(Kernel::Scalar);
+        kernels
+    }
+
+    pub fn lanes(self) -> usize {
+        match self {
+            Kernel::Avx512 => 16,
+            Kernel::Avx2 => 8,
+            Kernel::Sse2 | Kernel::Neon => 4,
+            Kernel::ShaNi | Kernel::Scalar => 1,
+        }
+    }
+}
+
+// what stays the same for every nonce of a block
+pub struct Job {
+    // the state after the blocks before the one the nonce starts in
+    midstate: [u32; 8],
+    // the blocks from that one on
+    tail: Vec<[u32; 16]>,
+    // where the nonce's high word is in `tail`, counted in words. the low word follows it.
+    nonce_word: usize,
+    hashing: Hashing,
+}
+
+impl Job {
+    // `words` as `Block::words` makes them, with the nonce at `nonce_index`
+    pub fn new(words: &[u32], nonce_index: u32, hashing: Hashing) -> Self {
+        let first = nonce_index as usize / 16;
+        assert!(
+            nonce_index as usize + 1 < words.len() && words.len().is_multiple_of(16),
+            "the nonce doesn't fit the words"
+        );
+        let mut midstate = util::H0;
+        let mut blocks = words
+            .chunks_exact(16)
+            .map(|block| <[u32; 16]>::try_from(block).unwrap());
+        for block in blocks.by_ref().take(first) {
+            util::compress(&mut midstate, &block);
+        }
+        Self {
+            midstate,
+            tail: blocks.collect(),
+            nonce_word: nonce_index as usize - first * 16,
+            hashing,
+        }
+    }
+
+    // the first of `nonces` whose hash has at least `k` leading zero nibbles. `kernel` has to be one of
+    // `Kernel::available`.
+    pub fn search(&self, kernel: Kernel, nonces: Range<u64>, k: u32) -> Option<u64> {
+        assert!(
+            Kernel::available().contains(&kernel),
+            "{kernel:?} isn't available"
+        );
+        // SAFETY: the cpu has the features of the kernel, checked just above
+        unsafe {
+            match kernel {
+                #[cfg(target_arch = "x86_64")]
+                Kernel::Avx512 => x86::search_avx512(self, nonces, k),
+                #[cfg(target_arch = "x86_64")]
+                Kernel::ShaNi => x86::search_sha_ni(self, nonces, k),
+                #[cfg(target_arch = "x86_64")]
+                Kernel::Avx2 => x86::search_avx2(self, nonces, k),
+                #[cfg(target_arch = "x86_64")]
+                Kernel::Sse2 => x86::search_sse2(self, nonces, k),
+                #[cfg(target_arch = "aarch64")]
+                Kernel::Neon => arm::search_neon(self, nonces, k),
+                Kernel::Scalar => search_lanes::<u32>(self, nonces, k),
+                #[allow(unreachable_patterns)]
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+fn leading_zero_nibbles(hash: &[u32; 8]) -> u32 {
+    let mut nibbles = 0;
+    for word in hash {
+        let zeros = word.leading_zeros() / 4;
+        nibbles += zeros;
+        if zeros < 8 {
+            break;
+        }
+    }
+    nibbles
+}
+
+// one u32 per lane. the methods are unsafe because the simd ones need their target features.
+trait Lanes: Copy {
+    const LANES: usize;
+    unsafe fn splat(x: u32) -> Self;
+    // the first `LANES` of `x`
+    unsafe fn load(x: &[u32]) -> Self;
+    unsafe fn store(self, out: &mut [u32]);
+    unsafe fn add(self, other: Self) -> Self;
+    unsafe fn xor(self, other: Self) -> Self;
+    unsafe fn and(self, other: Self) -> Self;
+    // `!self & other`
+    unsafe fn andnot(self, other: Self) -> Self;
+    unsafe fn or(self, other: Self) -> Self;
+    unsafe fn shr(self, n: u32) -> Self;
+    unsafe fn shl(self, n: u32) -> Self;
+
+    #[inline(always)]
+    unsafe fn rotr(self, n: u32) -> Self {
+        unsafe { self.shr(n).or(self.shl(32 - n)) }
+    }
+}
+
+impl Lanes for u32 {
+    const LANES: usize = 1;
+
+    unsafe fn splat(x: u32) -> Self {
+        x
+    }
+    unsafe fn load(x: &[u32]) -> Self {
+        x[0]
+    }
+    unsafe fn store(self, out: &mut [u32]) {
+        out[0] = self;
+    }
+    unsafe fn add(self, other: Self) -> Self {
+        self.wrapping_add(other)
+    }
+    unsafe fn xor(self, other: Self) -> Self {
+        self ^ other
+    }
+    unsafe fn and(self, other: Self) -> Self {
+        self & other
+    }
+    unsafe fn andnot(self, other: Self) -> Self {
+        !self & other
+    }
+    unsafe fn or(self, other: Self) -> Self {
+        self | other
+    }
+    unsafe fn shr(self, n: u32) -> Self {
+        self >> n
+    }
+    unsafe fn shl(self, n: u32) -> Self {
+        self << n
+    }
+    unsafe fn rotr(self, n: u32) -> Self {
+        self.rotate_right(n)
+    }
+}
+
+// util::compress, a lane at a time. inlined into the kernels, which enable the target features.
+#[inline(always)]
+unsafe fn compress<L: Lanes>(state: &mut [L; 8], block: &[L; 16]) {
+    unsafe {
+        let mut w = [L::splat(0); 64];
+        w[..16].copy_from_slice(block);
+        for t in 16..64 {
+            let s0 = w[t - 15]
+                .rotr(7)
+                .xor(w[t - 15].rotr(18))
+                .xor(w[t - 15].shr(3));
+            let s1 = w[t - 2]
+                .rotr(17)
+                .xor(w[t - 2].rotr(19))
+                .xor(w[t - 2].shr(10));
+            w[t] = s1.add(w[t - 7]).add(s0).add(w[t - 16]);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+        for (&k, &w) in util::K.iter().zip(&w) {
+            let s1 = e.rotr(6).xor(e.rotr(11)).xor(e.rotr(25));
+            let ch = e.and(f).xor(e.andnot(g));
+            let t1 = h.add(s1).add(ch).add(L::splat(k)).add(w);
+            let s0 = a.rotr(2).xor(a.rotr(13)).xor(a.rotr(22));
+            let maj = a.and(b).xor(a.and(c)).xor(b.and(c));
+            let t2 = s0.add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.add(t2);
+        }
+        for (word, working) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.add(working);
+        }
+    }
+}
+
+// util::digest_block, a lane at a time
+#[inline(always)]
+unsafe fn digest_block<L: Lanes>(digest: [L; 8]) -> [L; 16] {
+    unsafe {
+        let mut block = [L::splat(0); 16];
+        block[..8].copy_from_slice(&digest);
+        block[8] = L::splat(0x8000_0000);
+        block[15] = L::splat(256);
+        block
+    }
+}
+
+#[inline(always)]
+unsafe fn search_lanes<L: Lanes>(job: &Job, nonces: Range<u64>, k: u32) -> Option<u64> {
+    // the most lanes any kernel has
+    const MAX_LANES: usize = 16;
+    let mut high = [0; MAX_LANES];
+    let mut low = [0; MAX_LANES];
+    let mut first_words = [0; MAX_LANES];
+    let mut states = [[0; MAX_LANES]; 8];
+    let mut start = nonces.start;
+    while start < nonces.end {
+        for lane in 0..L::LANES {
+            let nonce = start.wrapping_add(lane as u64);
+            high[lane] = (nonce >> 32) as u32;
+            low[lane] = nonce as u32;
+        }
+        unsafe {
+            let mut state = job.midstate.map(|word| L::splat(word));
+            for (index, block) in job.tail.iter().enumerate() {
+                let mut words = block.map(|word| L::splat(word));
+                for (offset, nonce_words) in [(0, &high), (1, &low)] {
+                    let word = job.nonce_word + offset;
+                    if word / 16 == index {
+                        words[word % 16] = L::load(nonce_words);
+                    }
+                }
+                compress(&mut state, &words);
+            }
+            if job.hashing == Hashing::Sha256d {
+                let digest = state;
+                state = util::H0.map(|word| L::splat(word));
+                compress(&mut state, &digest_block(digest));
+            }
+
+            // most lanes fail on the first word, so only those that don't get the rest written out. one
+            // bit per lane that passed, lowest lane first.
+            state[0].store(&mut first_words);
+            let mut candidates = 0u32;
+            for (lane, word) in first_words[..L::LANES].iter().enumerate() {
+                if word.leading_zeros() / 4 >= k.min(8) {
+                    candidates |= 1 << lane;
+                }
+            }
+            if candidates != 0 {
+                for (word, out) in state.iter().zip(&mut states) {
+                    word.store(out);
+                }
+            }
+            while candidates != 0 {
+                let lane = candidates.trailing_zeros() as usize;
+                candidates &= candidates - 1;
+                let nonce = start + lane as u64;
+                let hash = states.map(|word| word[lane]);
+                if nonce < nonces.end && leading_zero_nibbles(&hash) >= k {
+                    return Some(nonce);
+                }
+            }
+        }
+        start = start.saturating_add(L::LANES as u64);
+    }
+    None
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+    use std::ops::Range;
+
+    use super::{Job, Lanes, leading_zero_nibbles, search_lanes};
+    use crate::sha_ni;
+    use crate::util::{self, Hashing};
+
+    #[derive(Clone, Copy)]
+    struct Sse2(__m128i);
+    #[derive(Clone, Copy)]
+    struct Avx2(__m256i);
+    #[derive(Clone, Copy)]
+    struct Avx512(__m512i);
+
+    impl Lanes for Sse2 {
+        const LANES: usize = 4;
+
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn splat(x: u32) -> Self {
+            Sse2(_mm_set1_epi32(x as i32))
+        }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn load(x: &[u32]) -> Self {
+            assert!(x.len() >= Self::LANES);
+            Sse2(unsafe { _mm_loadu_si128(x.as_ptr().cast()) })
+        }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn store(self, out: &mut [u32]) {
+            assert!(out.len() >= Self::LANES);
+            unsafe { _mm_storeu_si128(out.as_mut_ptr().cast(), self.0) }
+        }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn add(self, other: Self) -> Self {
+            Sse2(_mm_add_epi32(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn xor(self, other: Self) -> Self {
+            Sse2(_mm_xor_si128(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn and(self, other: Self) -> Self {
+            Sse2(_mm_and_si128(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn andnot(self, other: Self) -> Self {
+            Sse2(_mm_andnot_si128(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn or(self, other: Self) -> Self {
+            Sse2(_mm_or_si128(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn shr(self, n: u32) -> Self {
+            Sse2(_mm_srl_epi32(self.0, _mm_cvtsi32_si128(n as i32)))
+        }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn shl(self, n: u32) -> Self {
+            Sse2(_mm_sll_epi32(self.0, _mm_cvtsi32_si128(n as i32)))
+        }
+    }
+
+    impl Lanes for Avx2 {
+        const LANES: usize = 8;
+
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn splat(x: u32) -> Self {
+            Avx2(_mm256_set1_epi32(x as i32))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn load(x: &[u32]) -> Self {
+            assert!(x.len() >= Self::LANES);
+            Avx2(unsafe { _mm256_loadu_si256(x.as_ptr().cast()) })
+        }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn store(self, out: &mut [u32]) {
+            assert!(out.len() >= Self::LANES);
+            unsafe { _mm256_storeu_si256(out.as_mut_ptr().cast(), self.0) }
+        }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn add(self, other: Self) -> Self {
+            Avx2(_mm256_add_epi32(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn xor(self, other: Self) -> Self {
+            Avx2(_mm256_xor_si256(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn and(self, other: Self) -> Self {
+            Avx2(_mm256_and_si256(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn andnot(self, other: Self) -> Self {
+            Avx2(_mm256_andnot_si256(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn or(self, other: Self) -> Self {
+            Avx2(_mm256_or_si256(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn shr(self, n: u32) -> Self {
+            Avx2(_mm256_srl_epi32(self.0, _mm_cvtsi32_si128(n as i32)))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn shl(self, n: u32) -> Self {
+            Avx2(_mm256_sll_epi32(self.0, _mm_cvtsi32_si128(n as i32)))
+        }
+    }
+
+    impl Lanes for Avx512 {
+        const LANES: usize = 16;
+
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn splat(x: u32) -> Self {
+            Avx512(_mm512_set1_epi32(x as i32))
+        }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn load(x: &[u32]) -> Self {
+            assert!(x.len() >= Self::LANES);
+            Avx512(unsafe { _mm512_loadu_epi32(x.as_ptr().cast()) })
+        }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn store(self, out: &mut [u32]) {
+            assert!(out.len() >= Self::LANES);
+            unsafe { _mm512_storeu_epi32(out.as_mut_ptr().cast(), self.0) }
+        }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn add(self, other: Self) -> Self {
+            Avx512(_mm512_add_epi32(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn xor(self, other: Self) -> Self {
+            Avx512(_mm512_xor_si512(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn and(self, other: Self) -> Self {
+            Avx512(_mm512_and_si512(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn andnot(self, other: Self) -> Self {
+            Avx512(_mm512_andnot_si512(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn or(self, other: Self) -> Self {
+            Avx512(_mm512_or_si512(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn shr(self, n: u32) -> Self {
+            Avx512(_mm512_srl_epi32(self.0, _mm_cvtsi32_si128(n as i32)))
+        }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn shl(self, n: u32) -> Self {
+            Avx512(_mm512_sll_epi32(self.0, _mm_cvtsi32_si128(n as i32)))
+        }
+        // avx-512 rotates in one instruction
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn rotr(self, n: u32) -> Self {
+            Avx512(_mm512_rorv_epi32(self.0, _mm512_set1_epi32(n as i32)))
+        }
+    }
+
+    #[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
+    pub(super) unsafe fn search_sha_ni(job: &Job, nonces: Range<u64>, k: u32) -> Option<u64> {
+        let mut tail = job.tail.clone();
+        for nonce in nonces {
+            for (offset, word) in [(0, (nonce >> 32) as u32), (1, nonce as u32)] {
+                let index = job.nonce_word + offset;
+                tail[index / 16][index % 16] = word;
+            }
+            let mut state = job.midstate;
+            for block in &tail {
+                unsafe { sha_ni::compress(&mut state, block) };
+            }
+            if job.hashing == Hashing::Sha256d {
+                let digest = state;
+                state = util::H0;
+                unsafe { sha_ni::compress(&mut state, &util::digest_block(&digest)) };
+            }
+            if leading_zero_nibbles(&state) >= k {
+                return Some(nonce);
+            }
+        }
+        None
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn search_sse2(job: &Job, nonces: Range<u64>, k: u32) -> Option<u64> {
+        unsafe { search_lanes::<Sse2>(job, nonces, k) }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn search_avx2(job: &Job, nonces: Range<u64>, k: u32) -> Option<u64> {
+        unsafe { search_lanes::<Avx2>(job, nonces, k) }
+    }
+
+    #[target_feature(enable = "avx512f")]
+    pub(super) unsafe fn search_avx512(job: &Job, nonces: Range<u64>, k: u32) -> Option<u64> {
+        unsafe { search_lanes::<Avx512>(job, nonces, k) }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arm {
+    use std::arch::aarch64::*;
+    use std::ops::Range;
+
+    use super::{Job, Lanes, search_lanes};
+
+    #[derive(Clone, Copy)]
+    struct Neon(uint32x4_t);
+
+    impl Lanes for Neon {
+        const LANES: usize = 4;
+
+        #[inline]
+        #[target_feature(enable = "neon")]
+        unsafe fn splat(x: u32) -> Self {
+            Neon(vdupq_n_u32(x))
+        }
+        #[inline]
+        #[target_feature(enable = "neon")]
+        unsafe fn load(x: &[u32]) -> Self {
+            assert!(x.len() >= Self::LANES);
+            Neon(unsafe { vld1q_u32(x.as_ptr()) })
+        }
+        #[inline]
+        #[target_feature(enable = "neon")]
+        unsafe fn store(self, out: &mut [u32]) {
+            assert!(out.len() >= Self::LANES);
+            unsafe { vst1q_u32(out.as_mut_ptr(), self.0) }
+        }
+        #[inline]
+        #[target_feature(enable = "neon")]
+        unsafe fn add(self, other: Self) -> Self {
+            Neon(vaddq_u32(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "neon")]
+        unsafe fn xor(self, other: Self) -> Self {
+            Neon(veorq_u32(self.0, other.0))
+        }
+        #[inline]
+        #[target_feature(enable = "neon")]
+        unsafe fn and(self, other: Self) -> Self {
+            Neon(vandq_u32(self.0, other.0))
+        }
+        // vbic is `a & !b`
+        #[inline]
+        #[target_feature(enable = "neon")]
+        unsafe fn andnot(self, other: Self) -> Self {
+            Neon(vbicq_u32(other.0, self.0))
+        }
+        #[inline]
+        #[target_feature(enable = "neon")]
+        unsafe fn or(self, other: Self) -> Self {
+            Neon(vorrq_u32(self.0, other.0))
+        }
+        // a negative shift shifts right
+        #[inline]
+        #[target_feature(enable = "neon")]
+        unsafe fn shr(self, n: u32) -> Self {
+            Neon(vshlq_u32(self.0, vdupq_n_s32(-(n as i32))))
+        }
+        #[inline]
+        #[target_feature(enable = "neon")]
+        unsafe fn shl(self, n: u32) -> Self {
+            Neon(vshlq_u32(self.0, vdupq_n_s32(n as i32)))
+        }
+    }
+
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn search_neon(job: &Job, nonces: Range<u64>, k: u32) -> Option<u64> {
+        unsafe { search_lanes::<Neon>(job, nonces, k) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, RngCore, SeedableRng};
+
+    use super::*;
+
+    // the first nonce Hashing::hash_with_nonce says meets k
+    fn reference(
+        hashing: Hashing,
+        words: &[u32],
+        nonce_index: u32,
+        nonces: Range<u64>,
+        k: u32,
+    ) -> Option<u64> {
+        nonces.into_iter().find(|&nonce| {
+            util::hash_meets_k(&hashing.hash_with_nonce(words, nonce_index, nonce), k)
+        })
+    }
+
+    #[test]
+    fn kernels_match_the_scalar_hash() {
+        let mut rng = StdRng::seed_from_u64(47);
+        for round in 0..24 {
+            let mut data = vec![0; rng.gen_range(8..300)];
+            rng.fill_bytes(&mut data);
+            let words = util::to_words(&util::pad(&data));
+            // the last rounds put the nonce across two blocks
+            let nonce_index = if round < 20 {
+                rng.gen_range(0..data.len() as u32 / 4 - 1)
+            } else {
+                (data.len() as u32 / 4 - 2) / 16 * 16 + 15
+            };
+            if nonce_index as usize + 1 >= words.len() {
+                continue;
+            }
+            // odd lengths, and one across the 32 bit boundary of the low word
+            let start = match round % 3 {
+                0 => rng.gen_range(0..1 << 40),
+                1 => (1 << 32) - 37,
+                _ => 1,
+            };
+            let nonces = start..start + rng.gen_range(1..3000);
+            for hashing in [Hashing::Sha256, Hashing::Sha256d] {
+                let job = Job::new(&words, nonce_index, hashing);
+                for k in [1, 2, 3] {
+                    let expected = reference(hashing, &words, nonce_index, nonces.clone(), k);
+                    for kernel in Kernel::available() {
+                        assert_eq!(
+                            job.search(kernel, nonces.clone(), k),
+                            expected,
+                            "{kernel:?} {hashing}, round {round}, k = {k}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,133 +1,395 @@
 use std::fmt::Display;
 
 use chrono::{DateTime, Utc};
-use sha1::Digest;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
 
+use crate::ledger::{LedgerError, LedgerMode, OutPoint, Output};
 use crate::util;
+use crate::wallet;
 
-const MATRIKEL_NUMMER: u64 = 285765;
-const NAME: &str = "Daniel Budeanu";
-const RECIPIENTS: [&str; 3] = ["Alice", "Bob", "Carol"];
-const VALUES: [u64; 3] = [69, 420, 67];
+// defaults of the config
+pub(crate) const MATRIKEL_NUMMER: u64 = 285765;
+pub(crate) const RECIPIENTS: [&str; 3] = ["Alice", "Bob", "Carol"];
+pub(crate) const VALUES: [u64; 3] = [69, 420, 67];
+const FEE: u64 = 1;
 
-#[derive(Clone)]
+// the transactions `mine` makes: the genesis transaction is numbered `matrikel_nummer` and pays the first
+// recipient, every one after it pays the next recipient, starting over after the last one
+#[derive(Clone, Debug)]
+pub struct Coursework {
+    pub matrikel_nummer: u64,
+    pub recipients: Vec<String>,
+    // what each of `recipients` is paid
+    pub values: Vec<u64>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    Unsigned,
+    WrongSender,
+    Invalid,
+}
+
+impl Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureError::Unsigned => write!(f, "transaction is not signed"),
+            SignatureError::WrongSender => {
+                write!(f, "public key doesn't match the sender address")
+            }
+            SignatureError::Invalid => write!(f, "signature is invalid"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub(crate) transaction_number: u64,
-    pub(crate) name: &'static str,
+    // address of the public key that signed the transaction
+    pub(crate) sender: String,
     pub(crate) datetime: DateTime<Utc>,
-    pub(crate) recipient: &'static str,
-    pub(crate) value: u64,
-    pub(crate) iv: [u32; 8],
-    pub(crate) nonce: u64,
+    pub(crate) inputs: Vec<OutPoint>,
+    pub(crate) outputs: Vec<Output>,
+    // goes to the miner of the block, on top of the outputs
+    pub(crate) fee: u64,
+    pub(crate) public_key: Option<VerifyingKey>,
+    pub(crate) signature: Option<Signature>,
+}
+
+// the signed fields of a transaction, in the order they are signed in
+#[derive(Serialize)]
+struct Signed<'a> {
+    transaction_number: u64,
+    sender: &'a str,
+    datetime: &'a DateTime<Utc>,
+    inputs: &'a [OutPoint],
+    outputs: &'a [Output],
+    fee: u64,
 }
 
 impl Transaction {
-    pub fn new(old_transaction: Transaction, hash: [u32; 8]) -> Self {
-        let generation = old_transaction.transaction_number as usize + 1 - MATRIKEL_NUMMER as usize;
-        Self {
-            transaction_number: generation as u64 + MATRIKEL_NUMMER,
-            name: NAME,
+    // pays the block reward plus the fees of the block at `height` to the miner
+    pub fn coinbase(height: u64, value: u64, key: &SigningKey) -> Self {
+        let recipient = wallet::address(&key.verifying_key());
+        Self::coinbase_to(height, vec![Output { recipient, value }], key)
+    }
+
+    // splits the block reward plus the fees of the block at `height` between `outputs`, signed by the miner
+    pub fn coinbase_to(height: u64, outputs: Vec<Output>, key: &SigningKey) -> Self {
+        let mut transaction = Self {
+            transaction_number: height,
+            outputs,
+            sender: wallet::address(&key.verifying_key()),
             datetime: Utc::now(),
-            recipient: RECIPIENTS[generation],
-            value: VALUES[generation],
-            iv: hash.clone(),
-            nonce: u64::MAX,
+            inputs: vec![],
+            fee: 0,
+            public_key: None,
+            signature: None,
+        };
+        transaction.sign(key);
+        transaction
+    }
+
+    // the genesis transaction is paid for by `funding`, the coinbase of the genesis block. in utxo mode it
+    // spends the coinbase output, keeping what isn't paid to the first recipient as change, and fails if
+    // the coinbase can't cover the payment and the fee.
+    pub fn genesis(
+        mode: LedgerMode,
+        coursework: &Coursework,
+        funding: &Transaction,
+        key: &SigningKey,
+    ) -> Result<Self, LedgerError> {
+        let sender = wallet::address(&key.verifying_key());
+        let mut inputs = vec![];
+        let mut outputs = vec![Output {
+            recipient: coursework.recipients[0].clone(),
+            value: coursework.values[0],
+        }];
+        if mode == LedgerMode::Utxo {
+            inputs.push(OutPoint {
+                txid: funding.hash(),
+                index: 0,
+            });
+            let available = funding.outputs[0].value;
+            let spent = coursework.values[0]
+                .checked_add(FEE)
+                .ok_or(LedgerError::Overflow)?;
+            let change = available
+                .checked_sub(spent)
+                .ok_or(LedgerError::Unbalanced { available, spent })?;
+            outputs.push(Output {
+                recipient: sender.clone(),
+                value: change,
+            });
         }
+        let mut transaction = Self {
+            transaction_number: coursework.matrikel_nummer,
+            sender,
+            datetime: Utc::now(),
+            inputs,
+            outputs,
+            fee: FEE,
+            public_key: None,
+            signature: None,
+        };
+        transaction.sign(key);
+        Ok(transaction)
     }
 
-    pub fn _with_nonce(&self, nonce: u64) -> Self {
-        Self {
-            transaction_number: self.transaction_number,
-            name: self.name,
-            datetime: self.datetime,
-            recipient: self.recipient,
-            value: self.value,
-            iv: self.iv,
-            nonce,
+    pub fn new(
+        mode: LedgerMode,
+        coursework: &Coursework,
+        old_transaction: &Transaction,
+        key: &SigningKey,
+    ) -> Self {
+        let sender = wallet::address(&key.verifying_key());
+        let generation =
+            (old_transaction.transaction_number + 1 - coursework.matrikel_nummer) as usize;
+        // past the last recipient it starts over with the first one
+        let recipient = generation % coursework.recipients.len();
+        let mut inputs = vec![];
+        let mut outputs = vec![Output {
+            recipient: coursework.recipients[recipient].clone(),
+            value: coursework.values[recipient],
+        }];
+        if mode == LedgerMode::Utxo {
+            // spend the change output of the previous transaction
+            let index = old_transaction.outputs.len() - 1;
+            inputs.push(OutPoint {
+                txid: old_transaction.hash(),
+                index: index as u32,
+            });
+            let change = old_transaction.outputs[index]
+                .value
+                .saturating_sub(coursework.values[recipient] + FEE);
+            if change > 0 {
+                outputs.push(Output {
+                    recipient: sender.clone(),
+                    value: change,
+                });
+            }
         }
+        let mut transaction = Self {
+            transaction_number: generation as u64 + coursework.matrikel_nummer,
+            sender,
+            datetime: Utc::now(),
+            inputs,
+            outputs,
+            fee: FEE,
+            public_key: None,
+            signature: None,
+        };
+        transaction.sign(key);
+        transaction
     }
 
-    // NOTE: this SHOULD give the correct nonce offset. might have to look at it again if things start going wrong.
-    pub fn nonce_offset(&self) -> u32 {
-        let mut hasher = sha1::Sha1::new();
-        let transaction_string = format!(
-            "{transaction_number}{name}{datetime}{recipient}{value}",
-            transaction_number = self.transaction_number,
-            name = {
-                hasher.update(self.name);
-                hasher.finalize()[..]
-                    .iter()
-                    .map(|x| format!("{x:x}").chars().nth(0).unwrap())
-                    .collect::<String>()
-            },
-            datetime = self.datetime.to_rfc3339(),
-            recipient = self.recipient,
-            value = self.value,
-        );
-        let length = transaction_string.len();
-        let pad_length = util::calculate_manual_pad(length);
-        let pre_nonce_string = format!(
-            "{transaction_string:<pad_length$}{length:16}{iv0:08x}{iv1:08x}{iv2:08x}{iv3:08x}{iv4:08x}{iv5:08x}{iv6:08x}{iv7:08x}",
-            iv0 = self.iv[0],
-            iv1 = self.iv[1],
-            iv2 = self.iv[2],
-            iv3 = self.iv[3],
-            iv4 = self.iv[4],
-            iv5 = self.iv[5],
-            iv6 = self.iv[6],
-            iv7 = self.iv[7],
-        );
-        let word_idx = pre_nonce_string.len() / 4;
-        word_idx as u32
+    // pays `outputs` from `inputs` in utxo mode, or from the sender's balance in account mode, signed by the
+    // sender
+    pub fn payment(
+        transaction_number: u64,
+        inputs: Vec<OutPoint>,
+        outputs: Vec<Output>,
+        fee: u64,
+        key: &SigningKey,
+    ) -> Self {
+        let mut transaction = Self {
+            transaction_number,
+            sender: wallet::address(&key.verifying_key()),
+            datetime: Utc::now(),
+            inputs,
+            outputs,
+            fee,
+            public_key: None,
+            signature: None,
+        };
+        transaction.sign(key);
+        transaction
+    }
+
+    pub fn transaction_number(&self) -> u64 {
+        self.transaction_number
+    }
+
+    pub fn sender(&self) -> &str {
+        &self.sender
+    }
+
+    pub fn datetime(&self) -> DateTime<Utc> {
+        self.datetime
+    }
+
+    pub fn inputs(&self) -> &[OutPoint] {
+        &self.inputs
+    }
+
+    pub fn outputs(&self) -> &[Output] {
+        &self.outputs
+    }
+
+    pub fn fee(&self) -> u64 {
+        self.fee
+    }
+
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.sender = wallet::address(&key.verifying_key());
+        self.public_key = Some(key.verifying_key());
+        self.signature = Some(key.sign(self.signing_string().as_bytes()));
+    }
+
+    pub fn verify_signature(&self) -> Result<(), SignatureError> {
+        let (Some(public_key), Some(signature)) = (&self.public_key, &self.signature) else {
+            return Err(SignatureError::Unsigned);
+        };
+        if wallet::address(public_key) != self.sender {
+            return Err(SignatureError::WrongSender);
+        }
+        public_key
+            .verify(self.signing_string().as_bytes(), signature)
+            .map_err(|_| SignatureError::Invalid)
+    }
+
+    // everything but the signature itself is signed. the fields are written out as json, so each one is
+    // delimited and no digits can move from one to the next without changing the signature.
+    fn signing_string(&self) -> String {
+        serde_json::to_string(&Signed {
+            transaction_number: self.transaction_number,
+            sender: &self.sender,
+            datetime: &self.datetime,
+            inputs: &self.inputs,
+            outputs: &self.outputs,
+            fee: self.fee,
+        })
+        .expect("transactions always serialize")
+    }
+
+    // the txid
+    pub fn hash(&self) -> [u32; 8] {
+        let padded = util::pad(self.to_string().as_bytes());
+        util::sha256(&util::to_words(&padded))
     }
 }
 
 impl Display for Transaction {
-    // NOTE: this SHOULD give the properly padded string. might have to look at it again if things start going wrong.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut hasher = sha1::Sha1::new();
-        let transaction_string = format!(
-            "{transaction_number}{name}{datetime}{recipient}{value}",
-            transaction_number = self.transaction_number,
-            name = {
-                hasher.update(self.name);
-                hasher.finalize()[..]
-                    .iter()
-                    .map(|x| format!("{x:x}").chars().nth(0).unwrap())
-                    .collect::<String>()
-            },
-            datetime = self.datetime.to_rfc3339(),
-            recipient = self.recipient,
-            value = self.value,
-        );
-        let length = transaction_string.len();
-        let pad_length = util::calculate_manual_pad(length);
-        let transaction_string = format!(
-            "{transaction_string:<pad_length$}{length:<16}{iv0:08x}{iv1:08x}{iv2:08x}{iv3:08x}{iv4:08x}{iv5:08x}{iv6:08x}{iv7:08x}{nonce}",
-            iv0 = self.iv[0],
-            iv1 = self.iv[1],
-            iv2 = self.iv[2],
-            iv3 = self.iv[3],
-            iv4 = self.iv[4],
-            iv5 = self.iv[5],
-            iv6 = self.iv[6],
-            iv7 = self.iv[7],
-            nonce = util::nonce_to_raw_string(self.nonce),
-        );
-        write!(f, "{transaction_string}")
+        let signature = self
+            .signature
+            .map(|signature| hex::encode(signature.to_bytes()))
+            .unwrap_or_default();
+        write!(f, "{}{signature}", self.signing_string())
     }
 }
 
-impl Default for Transaction {
-    fn default() -> Self {
-        Self {
-            transaction_number: MATRIKEL_NUMMER,
-            name: NAME,
-            datetime: Utc::now(),
-            recipient: RECIPIENTS[0],
-            value: VALUES[0],
-            iv: [0, 0, 0, 0, 0, 0, 0, 0],
-            nonce: u64::MAX,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payment(key: &SigningKey) -> Transaction {
+        let outputs = vec![Output {
+            recipient: "Alice".to_string(),
+            value: 69,
+        }];
+        Transaction::payment(1, vec![], outputs, 1, key)
+    }
+
+    #[test]
+    fn verifies_its_own_signature() {
+        let transaction = payment(&SigningKey::from_bytes(&[1; 32]));
+        assert_eq!(transaction.verify_signature(), Ok(()));
+        let coinbase = Transaction::coinbase(0, 100, &SigningKey::from_bytes(&[1; 32]));
+        assert_eq!(coinbase.verify_signature(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_tampered_fields() {
+        let original = payment(&SigningKey::from_bytes(&[1; 32]));
+        let tampered: [fn(&mut Transaction); 5] = [
+            |transaction| transaction.transaction_number += 1,
+            |transaction| transaction.datetime += chrono::Duration::seconds(1),
+            |transaction| transaction.outputs[0].recipient = "Mallory".to_string(),
+            |transaction| transaction.outputs[0].value += 1,
+            |transaction| transaction.fee = 0,
+        ];
+        for tamper in tampered {
+            let mut transaction = original.clone();
+            tamper(&mut transaction);
+            assert_eq!(transaction.verify_signature(), Err(SignatureError::Invalid));
         }
     }
+
+    #[test]
+    fn digits_cant_move_between_fields() {
+        // 69 with a fee of 1 and 6 with a fee of 91 read the same once the fields are glued together
+        let original = payment(&SigningKey::from_bytes(&[1; 32]));
+        let mut moved = original.clone();
+        moved.outputs[0].value = 6;
+        moved.fee = 91;
+        assert_eq!(moved.verify_signature(), Err(SignatureError::Invalid));
+        assert_ne!(moved.hash(), original.hash());
+
+        let mut moved = original.clone();
+        moved.outputs[0].recipient = "Alice6".to_string();
+        moved.outputs[0].value = 9;
+        assert_eq!(moved.verify_signature(), Err(SignatureError::Invalid));
+        assert_ne!(moved.to_string(), original.to_string());
+    }
+
+    #[test]
+    fn rejects_the_wrong_key() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let mallory = SigningKey::from_bytes(&[2; 32]);
+
+        // signed by mallory in alice's name
+        let mut transaction = payment(&mallory);
+        transaction.sender = wallet::address(&alice.verifying_key());
+        assert_eq!(
+            transaction.verify_signature(),
+            Err(SignatureError::WrongSender)
+        );
+
+        // alice's key and address with mallory's signature
+        let mut transaction = payment(&alice);
+        transaction.signature = payment(&mallory).signature;
+        assert_eq!(transaction.verify_signature(), Err(SignatureError::Invalid));
+
+        let mut transaction = payment(&alice);
+        transaction.signature = None;
+        assert_eq!(
+            transaction.verify_signature(),
+            Err(SignatureError::Unsigned)
+        );
+    }
+
+    // the coinbase pays 100: 98 and its fee of 1 leave 1 in change, 100 can't be paid at all
+    #[test]
+    fn genesis_change_covers_the_payment() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let coinbase = Transaction::coinbase(0, 100, &key);
+        let coursework = |value| Coursework {
+            matrikel_nummer: MATRIKEL_NUMMER,
+            recipients: vec!["Alice".to_string()],
+            values: vec![value],
+        };
+
+        let genesis =
+            Transaction::genesis(LedgerMode::Utxo, &coursework(98), &coinbase, &key).unwrap();
+        assert_eq!(genesis.outputs[1].value, 1);
+        assert_eq!(
+            Transaction::genesis(LedgerMode::Utxo, &coursework(100), &coinbase, &key).err(),
+            Some(LedgerError::Unbalanced {
+                available: 100,
+                spent: 101
+            })
+        );
+        assert_eq!(
+            Transaction::genesis(LedgerMode::Utxo, &coursework(u64::MAX), &coinbase, &key).err(),
+            Some(LedgerError::Overflow)
+        );
+        // the account ledger checks the balance when the block is applied
+        assert!(
+            Transaction::genesis(LedgerMode::Account, &coursework(100), &coinbase, &key).is_ok()
+        );
+    }
 }
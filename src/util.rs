@@ -1,10 +1,74 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(target_arch = "x86_64")]
+use crate::sha_ni;
+
+// what a chain's block hashes are. sha256d hashes the digest once more, the way bitcoin hashes its headers,
+// so blocks can be checked against bitcoin tools.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Hashing {
+    #[default]
+    Sha256,
+    Sha256d,
+}
+
+impl Hashing {
+    pub fn is_sha256(&self) -> bool {
+        *self == Hashing::Sha256
+    }
+
+    // `words` are padded, as `to_words(&pad(..))` makes them
+    pub fn hash(self, words: &[u32]) -> [u32; 8] {
+        match self {
+            Hashing::Sha256 => sha256(words),
+            Hashing::Sha256d => sha256d(words),
+        }
+    }
+
+    // `hash_with_nonce` in this mode
+    pub fn hash_with_nonce(self, words: &[u32], nonce_index: u32, nonce: u64) -> [u32; 8] {
+        let hash = hash_with_nonce(words, nonce_index, nonce);
+        match self {
+            Hashing::Sha256 => hash,
+            Hashing::Sha256d => rehash(&hash),
+        }
+    }
+}
+
+impl Display for Hashing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hashing::Sha256 => write!(f, "sha256"),
+            Hashing::Sha256d => write!(f, "sha256d"),
+        }
+    }
+}
+
+impl FromStr for Hashing {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "sha256" => Ok(Hashing::Sha256),
+            "sha256d" => Ok(Hashing::Sha256d),
+            _ => Err(format!(
+                "unknown hashing {value}, expected sha256 or sha256d"
+            )),
+        }
+    }
+}
+
 // calculate manual padding for string to align the nonce such that it has its own two words
 pub fn calculate_manual_pad(size: usize) -> usize {
-    return size % 4;
+    size % 4
 }
 
-pub fn _calculate_k(size: usize) -> usize {
-    return (448 - 1 - size % 512 + 512) % 512;
+fn _calculate_k(size: usize) -> usize {
+    (448 - 1 - size % 512 + 512) % 512
 }
 
 pub fn pad(data: &[u8]) -> Vec<u8> {
@@ -20,17 +84,15 @@ pub fn pad(data: &[u8]) -> Vec<u8> {
         64 + 56 - current_len
     };
 
-    for _ in 0..k {
-        out.push(0x00);
-    }
+    out.extend(std::iter::repeat_n(0x00, k));
 
     out.extend_from_slice(&original_bit_size.to_be_bytes());
 
     out
 }
 
-pub fn to_words(data: &Vec<u8>) -> Vec<u32> {
-    assert!(data.len() % 64 == 0);
+pub fn to_words(data: &[u8]) -> Vec<u32> {
+    assert!(data.len().is_multiple_of(64));
     (0..data.len() / 4)
         .map(|i| {
             ((data[i * 4] as u32) << 24)
@@ -50,7 +112,7 @@ pub fn nonce_to_raw_string(nonce: u64) -> String {
     out
 }
 
-const K: [u32; 64] = [
+pub(crate) const K: [u32; 64] = [
     0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
     0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
     0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
@@ -62,7 +124,7 @@ const K: [u32; 64] = [
 ];
 
 fn rotr(x: u32, n: u32) -> u32 {
-    (x >> n) | (x << (32 - n))
+    x.rotate_right(n)
 }
 
 fn ch(x: u32, y: u32, z: u32) -> u32 {
@@ -89,57 +151,99 @@ fn ssig1(x: u32) -> u32 {
     rotr(x, 17) ^ rotr(x, 19) ^ (x >> 10)
 }
 
+// messages and their digests from FIPS 180-2 and the NIST examples, for the tests and the gpu self-test
+pub const NIST_VECTORS: [(&str, &str); 4] = [
+    (
+        "",
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+    ),
+    (
+        "abc",
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+    ),
+    (
+        "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+        "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+    ),
+    (
+        "abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu",
+        "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1",
+    ),
+];
+
+// the initial hash value
+pub(crate) const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
 pub fn sha256(words: &[u32]) -> [u32; 8] {
-    let mut working_h: [u32; 8] = [
-        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
-        0x5be0cd19,
-    ];
-    let chunk_count = words.len() / 16;
-    for i in 0..chunk_count {
-        let mut w = [0u32; 64];
-        for t in 0..16 {
-            w[t] = words[16 * i + t];
-        }
-        for t in 16..64 {
-            w[t] = ssig1(w[t - 2])
-                .wrapping_add(w[t - 7])
-                .wrapping_add(ssig0(w[t - 15]))
-                .wrapping_add(w[t - 16]);
-        }
-        let mut a: u32 = working_h[0];
-        let mut b: u32 = working_h[1];
-        let mut c: u32 = working_h[2];
-        let mut d: u32 = working_h[3];
-        let mut e: u32 = working_h[4];
-        let mut f: u32 = working_h[5];
-        let mut g: u32 = working_h[6];
-        let mut h: u32 = working_h[7];
-        for t in 0..64 {
-            let t1 = h
-                .wrapping_add(bsig1(e))
-                .wrapping_add(ch(e, f, g))
-                .wrapping_add(K[t])
-                .wrapping_add(w[t]);
-            let t2 = bsig0(a).wrapping_add(maj(a, b, c));
-            h = g;
-            g = f;
-            f = e;
-            e = d.wrapping_add(t1);
-            d = c;
-            c = b;
-            b = a;
-            a = t1.wrapping_add(t2);
-        }
-        working_h[0] = working_h[0].wrapping_add(a);
-        working_h[1] = working_h[1].wrapping_add(b);
-        working_h[2] = working_h[2].wrapping_add(c);
-        working_h[3] = working_h[3].wrapping_add(d);
-        working_h[4] = working_h[4].wrapping_add(e);
-        working_h[5] = working_h[5].wrapping_add(f);
-        working_h[6] = working_h[6].wrapping_add(g);
-        working_h[7] = working_h[7].wrapping_add(h);
+    let mut state = H0;
+    for block in words.chunks_exact(16) {
+        compress(&mut state, block.try_into().unwrap());
+    }
+    state
+}
+
+// sha256 of the 32 byte sha256 digest of `words`
+pub fn sha256d(words: &[u32]) -> [u32; 8] {
+    rehash(&sha256(words))
+}
+
+// a digest padded to the one block it fits in
+pub(crate) fn digest_block(digest: &[u32; 8]) -> [u32; 16] {
+    let mut block = [0; 16];
+    block[..8].copy_from_slice(digest);
+    block[8] = 0x8000_0000;
+    block[15] = 256;
+    block
+}
+
+fn rehash(digest: &[u32; 8]) -> [u32; 8] {
+    let mut state = H0;
+    compress(&mut state, &digest_block(digest));
+    state
+}
+
+// one block of 16 words into `state`, on the sha extensions where the cpu has them
+pub fn compress(state: &mut [u32; 8], block: &[u32; 16]) {
+    #[cfg(target_arch = "x86_64")]
+    if sha_ni::available() {
+        // SAFETY: just checked
+        unsafe { sha_ni::compress(state, block) };
+        return;
+    }
+    compress_portable(state, block);
+}
+
+pub(crate) fn compress_portable(state: &mut [u32; 8], block: &[u32; 16]) {
+    let mut w = [0u32; 64];
+    w[..16].copy_from_slice(block);
+    for t in 16..64 {
+        w[t] = ssig1(w[t - 2])
+            .wrapping_add(w[t - 7])
+            .wrapping_add(ssig0(w[t - 15]))
+            .wrapping_add(w[t - 16]);
+    }
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for t in 0..64 {
+        let t1 = h
+            .wrapping_add(bsig1(e))
+            .wrapping_add(ch(e, f, g))
+            .wrapping_add(K[t])
+            .wrapping_add(w[t]);
+        let t2 = bsig0(a).wrapping_add(maj(a, b, c));
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+    for (word, working) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *word = word.wrapping_add(working);
     }
-    working_h
 }
 
 pub fn check_k_nibbles(hash: &[u8], k: u32) -> bool {
@@ -167,19 +271,35 @@ pub fn check_k_nibbles(hash: &[u8], k: u32) -> bool {
             return true;
         }
     }
-    return zero_nibbles >= k;
+    zero_nibbles >= k
 }
 
-pub fn hash_to_iv(hash: &Vec<u8>) -> [u32; 8] {
-    assert!(hash.len() == 32);
+// hashes `words` with `nonce` written at `nonce_index`, the way the shader does
+pub fn hash_with_nonce(words: &[u32], nonce_index: u32, nonce: u64) -> [u32; 8] {
+    let mut words = words.to_vec();
+    let idx = nonce_index as usize;
+    words[idx] = (nonce >> 32) as u32;
+    words[idx + 1] = (nonce & 0xFFFFFFFF) as u32;
+    sha256(&words)
+}
 
-    let mut out: [u32; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
+pub fn hash_meets_k(hash: &[u32; 8], k: u32) -> bool {
+    let bytes: Vec<u8> = hash.iter().flat_map(|&w| w.to_be_bytes()).collect();
+    check_k_nibbles(&bytes, k)
+}
 
-    for i in 0..8 {
-        for j in 0..4 {
-            out[i] |= (hash[i * 4 + j] as u32) << (24 - (j * 8));
-        }
-    }
+pub fn hash_to_hex(hash: &[u32; 8]) -> String {
+    hash.iter().map(|w| format!("{w:08x}")).collect()
+}
 
-    out
+pub fn hex_to_hash(hex: &str) -> Option<[u32; 8]> {
+    // from_str_radix alone would let a sign through
+    if hex.len() != 64 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut hash = [0; 8];
+    for (i, word) in hash.iter_mut().enumerate() {
+        *word = u32::from_str_radix(&hex[i * 8..i * 8 + 8], 16).ok()?;
+    }
+    Some(hash)
 }
@@ -0,0 +1,462 @@
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+pub const DEFAULT_KEY: &str = "default";
+const VERSION: u32 = 1;
+pub const PASSPHRASE_VAR: &str = "TOY_BLOCKCHAIN_PASSPHRASE";
+pub const NEW_PASSPHRASE_VAR: &str = "TOY_BLOCKCHAIN_NEW_PASSPHRASE";
+// associated data of the sealed empty message that tells a wrong passphrase apart from a corrupted key
+const CHECK_AAD: &[u8] = b"toy_blockchain wallet";
+
+// senders are identified by the first 20 bytes of the sha256 of their public key
+pub fn address(public_key: &VerifyingKey) -> String {
+    hex::encode(&Sha256::digest(public_key.as_bytes())[..20])
+}
+
+#[derive(Debug)]
+pub enum WalletError {
+    Io(io::Error),
+    Format(serde_json::Error),
+    UnsupportedVersion(u32),
+    Exists(PathBuf),
+    Missing(PathBuf),
+    WrongPassphrase,
+    PassphraseMismatch,
+    InvalidName(String),
+    DuplicateKey(String),
+    UnknownKey(String),
+    Corrupted(String),
+}
+
+impl Display for WalletError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WalletError::Io(error) => write!(f, "{error}"),
+            WalletError::Format(error) => write!(f, "malformed wallet file: {error}"),
+            WalletError::UnsupportedVersion(version) => {
+                write!(f, "unsupported wallet version {version}")
+            }
+            WalletError::Exists(path) => write!(f, "{} already exists", path.display()),
+            WalletError::Missing(path) => write!(
+                f,
+                "no wallet at {}, create one with `wallet create`",
+                path.display()
+            ),
+            WalletError::WrongPassphrase => write!(f, "wrong passphrase"),
+            WalletError::PassphraseMismatch => write!(f, "passphrases don't match"),
+            WalletError::InvalidName(name) => write!(f, "invalid key name \"{name}\""),
+            WalletError::DuplicateKey(name) => write!(f, "key \"{name}\" already exists"),
+            WalletError::UnknownKey(name) => write!(f, "no key named \"{name}\""),
+            WalletError::Corrupted(name) => write!(f, "key \"{name}\" is corrupted"),
+        }
+    }
+}
+
+impl std::error::Error for WalletError {}
+
+impl From<io::Error> for WalletError {
+    fn from(error: io::Error) -> Self {
+        WalletError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for WalletError {
+    fn from(error: serde_json::Error) -> Self {
+        WalletError::Format(error)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Kdf {
+    salt: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+impl Kdf {
+    fn generate() -> Self {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        Self {
+            salt: hex::encode(salt),
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+
+    // argon2id turns the passphrase into the chacha20poly1305 key
+    fn derive(&self, passphrase: &str) -> Result<Zeroizing<[u8; 32]>, WalletError> {
+        let salt = hex::decode(&self.salt).map_err(|_| WalletError::Corrupted("kdf".into()))?;
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+            .map_err(|_| WalletError::Corrupted("kdf".into()))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, key.as_mut())
+            .map_err(|_| WalletError::Corrupted("kdf".into()))?;
+        Ok(key)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Sealed {
+    nonce: String,
+    ciphertext: String,
+}
+
+impl Sealed {
+    fn seal(key: &[u8; 32], message: &[u8], aad: &[u8]) -> Self {
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key))
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: message, aad })
+            .expect("failed to encrypt");
+        Self {
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        }
+    }
+
+    fn open(&self, key: &[u8; 32], aad: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+        let nonce: [u8; 12] = hex::decode(&self.nonce).ok()?.try_into().ok()?;
+        let ciphertext = hex::decode(&self.ciphertext).ok()?;
+        ChaCha20Poly1305::new(Key::from_slice(key))
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+            .map(Zeroizing::new)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredKey {
+    name: String,
+    public_key: VerifyingKey,
+    // the secret key, sealed with the key name as associated data
+    secret: Sealed,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct WalletFile {
+    version: u32,
+    kdf: Kdf,
+    check: Sealed,
+    keys: Vec<StoredKey>,
+}
+
+// public keys are stored in the clear, so a locked wallet can still be listed
+pub struct Wallet {
+    pub(crate) path: PathBuf,
+    file: WalletFile,
+}
+
+pub struct UnlockedWallet {
+    wallet: Wallet,
+    key: Zeroizing<[u8; 32]>,
+}
+
+impl Wallet {
+    pub fn open(path: &Path) -> Result<Self, WalletError> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                return Err(WalletError::Missing(path.to_path_buf()));
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let file: WalletFile = serde_json::from_slice(&data)?;
+        if file.version != VERSION {
+            return Err(WalletError::UnsupportedVersion(file.version));
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    pub fn create(path: &Path, passphrase: &str) -> Result<UnlockedWallet, WalletError> {
+        if path.exists() {
+            return Err(WalletError::Exists(path.to_path_buf()));
+        }
+        let kdf = Kdf::generate();
+        let key = kdf.derive(passphrase)?;
+        let wallet = UnlockedWallet {
+            wallet: Wallet {
+                path: path.to_path_buf(),
+                file: WalletFile {
+                    version: VERSION,
+                    check: Sealed::seal(&key, &[], CHECK_AAD),
+                    kdf,
+                    keys: vec![],
+                },
+            },
+            key,
+        };
+        wallet.save()?;
+        Ok(wallet)
+    }
+
+    pub fn unlock(self, passphrase: &str) -> Result<UnlockedWallet, WalletError> {
+        let key = self.file.kdf.derive(passphrase)?;
+        if self.file.check.open(&key, CHECK_AAD).is_none() {
+            return Err(WalletError::WrongPassphrase);
+        }
+        Ok(UnlockedWallet { wallet: self, key })
+    }
+
+    pub fn list(&self) -> Vec<(&str, &VerifyingKey)> {
+        self.file
+            .keys
+            .iter()
+            .map(|key| (key.name.as_str(), &key.public_key))
+            .collect()
+    }
+}
+
+impl UnlockedWallet {
+    pub fn list(&self) -> Vec<(&str, &VerifyingKey)> {
+        self.wallet.list()
+    }
+
+    fn save(&self) -> Result<(), WalletError> {
+        let data = serde_json::to_vec_pretty(&self.wallet.file)?;
+        let tmp_path = self.wallet.path.with_extension("tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        io::Write::write_all(&mut options.open(&tmp_path)?, &data)?;
+        fs::rename(tmp_path, &self.wallet.path)?;
+        Ok(())
+    }
+
+    pub fn add_key(&mut self, name: &str) -> Result<VerifyingKey, WalletError> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(WalletError::InvalidName(name.to_string()));
+        }
+        if self.wallet.file.keys.iter().any(|key| key.name == name) {
+            return Err(WalletError::DuplicateKey(name.to_string()));
+        }
+        let key = SigningKey::generate(&mut OsRng);
+        self.wallet.file.keys.push(StoredKey {
+            name: name.to_string(),
+            public_key: key.verifying_key(),
+            secret: Sealed::seal(&self.key, key.as_bytes(), name.as_bytes()),
+        });
+        self.save()?;
+        Ok(key.verifying_key())
+    }
+
+    pub fn key(&self, name: &str) -> Result<SigningKey, WalletError> {
+        let stored = self
+            .wallet
+            .file
+            .keys
+            .iter()
+            .find(|key| key.name == name)
+            .ok_or_else(|| WalletError::UnknownKey(name.to_string()))?;
+        let secret = stored
+            .secret
+            .open(&self.key, name.as_bytes())
+            .ok_or_else(|| WalletError::Corrupted(name.to_string()))?;
+        let bytes: &[u8; 32] = secret
+            .as_slice()
+            .try_into()
+            .map_err(|_| WalletError::Corrupted(name.to_string()))?;
+        let key = SigningKey::from_bytes(bytes);
+        if key.verifying_key() != stored.public_key {
+            return Err(WalletError::Corrupted(name.to_string()));
+        }
+        Ok(key)
+    }
+
+    // re-seals every key under a fresh salt
+    pub fn change_passphrase(&mut self, passphrase: &str) -> Result<(), WalletError> {
+        let kdf = Kdf::generate();
+        let key = kdf.derive(passphrase)?;
+        let mut keys = vec![];
+        for stored in &self.wallet.file.keys {
+            let secret = self.key(&stored.name)?;
+            keys.push(StoredKey {
+                secret: Sealed::seal(&key, secret.as_bytes(), stored.name.as_bytes()),
+                ..stored.clone()
+            });
+        }
+        self.wallet.file = WalletFile {
+            version: VERSION,
+            check: Sealed::seal(&key, &[], CHECK_AAD),
+            kdf,
+            keys,
+        };
+        self.key = key;
+        self.save()
+    }
+}
+
+// reads the passphrase from TOY_BLOCKCHAIN_PASSPHRASE or prompts for it
+pub fn read_passphrase(prompt: &str) -> Result<Zeroizing<String>, WalletError> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_VAR) {
+        return Ok(Zeroizing::new(passphrase));
+    }
+    Ok(Zeroizing::new(rpassword::prompt_password(prompt)?))
+}
+
+// `var` lets scripts pass the passphrase, TOY_BLOCKCHAIN_NEW_PASSPHRASE when changing it
+pub fn read_new_passphrase(var: &str) -> Result<Zeroizing<String>, WalletError> {
+    if let Ok(passphrase) = std::env::var(var) {
+        return Ok(Zeroizing::new(passphrase));
+    }
+    let passphrase = Zeroizing::new(rpassword::prompt_password("new passphrase: ")?);
+    let repeated = Zeroizing::new(rpassword::prompt_password("repeat passphrase: ")?);
+    if passphrase != repeated {
+        return Err(WalletError::PassphraseMismatch);
+    }
+    Ok(passphrase)
+}
+
+// unlocks the wallet for mining, creating it together with the default key on first use
+pub fn unlock_default(path: &Path) -> Result<SigningKey, WalletError> {
+    let mut wallet = match Wallet::open(path) {
+        Ok(wallet) => wallet.unlock(&read_passphrase("wallet passphrase: ")?)?,
+        Err(WalletError::Missing(_)) => {
+            eprintln!("creating a new wallet at {}", path.display());
+            Wallet::create(path, &read_new_passphrase(PASSPHRASE_VAR)?)?
+        }
+        Err(error) => return Err(error),
+    };
+    if !wallet
+        .wallet
+        .list()
+        .iter()
+        .any(|(name, _)| *name == DEFAULT_KEY)
+    {
+        wallet.add_key(DEFAULT_KEY)?;
+    }
+    wallet.key(DEFAULT_KEY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a wallet file of its own for every test, removed again when it's done
+    struct TestPath(PathBuf);
+
+    impl TestPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("wallet-test-{name}-{}.json", std::process::id()));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TestPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let path = TestPath::new("round-trip");
+        let mut wallet = Wallet::create(&path.0, "hunter2").unwrap();
+        let public_key = wallet.add_key(DEFAULT_KEY).unwrap();
+        let secret = wallet.key(DEFAULT_KEY).unwrap();
+
+        let wallet = Wallet::open(&path.0).unwrap();
+        assert_eq!(wallet.list(), [(DEFAULT_KEY, &public_key)]);
+        let wallet = wallet.unlock("hunter2").unwrap();
+        assert_eq!(wallet.key(DEFAULT_KEY).unwrap(), secret);
+        assert!(matches!(
+            wallet.key("other"),
+            Err(WalletError::UnknownKey(_))
+        ));
+        assert!(matches!(
+            Wallet::create(&path.0, "hunter2"),
+            Err(WalletError::Exists(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_the_wrong_passphrase() {
+        let path = TestPath::new("wrong-passphrase");
+        Wallet::create(&path.0, "hunter2").unwrap();
+        assert!(matches!(
+            Wallet::open(&path.0).unwrap().unlock("hunter3"),
+            Err(WalletError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn changing_the_passphrase_keeps_the_keys() {
+        let path = TestPath::new("change-passphrase");
+        let mut wallet = Wallet::create(&path.0, "hunter2").unwrap();
+        wallet.add_key(DEFAULT_KEY).unwrap();
+        let secret = wallet.key(DEFAULT_KEY).unwrap();
+        wallet.change_passphrase("correct horse").unwrap();
+
+        assert!(matches!(
+            Wallet::open(&path.0).unwrap().unlock("hunter2"),
+            Err(WalletError::WrongPassphrase)
+        ));
+        let wallet = Wallet::open(&path.0)
+            .unwrap()
+            .unlock("correct horse")
+            .unwrap();
+        assert_eq!(wallet.key(DEFAULT_KEY).unwrap(), secret);
+    }
+
+    #[test]
+    fn fails_cleanly_on_damaged_files() {
+        let path = TestPath::new("damaged");
+        let mut wallet = Wallet::create(&path.0, "hunter2").unwrap();
+        wallet.add_key(DEFAULT_KEY).unwrap();
+        let data = fs::read(&path.0).unwrap();
+
+        fs::write(&path.0, &data[..data.len() / 2]).unwrap();
+        assert!(matches!(Wallet::open(&path.0), Err(WalletError::Format(_))));
+
+        let mut file: WalletFile = serde_json::from_slice(&data).unwrap();
+        file.version += 1;
+        fs::write(&path.0, serde_json::to_vec(&file).unwrap()).unwrap();
+        assert!(matches!(
+            Wallet::open(&path.0),
+            Err(WalletError::UnsupportedVersion(_))
+        ));
+
+        // flip a bit of the sealed secret, which the passphrase check doesn't cover
+        let mut file: WalletFile = serde_json::from_slice(&data).unwrap();
+        let mut ciphertext = hex::decode(&file.keys[0].secret.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        file.keys[0].secret.ciphertext = hex::encode(ciphertext);
+        fs::write(&path.0, serde_json::to_vec(&file).unwrap()).unwrap();
+        let wallet = Wallet::open(&path.0).unwrap().unlock("hunter2").unwrap();
+        assert!(matches!(
+            wallet.key(DEFAULT_KEY),
+            Err(WalletError::Corrupted(_))
+        ));
+    }
+}
@@ -0,0 +1,67 @@
+// the library on its own, through nothing but its public api
+
+use ed25519_dalek::SigningKey;
+use toy_blockchain::block::Block;
+use toy_blockchain::chain::Chain;
+use toy_blockchain::ledger::{LedgerMode, Output};
+use toy_blockchain::miner::{CpuMiner, Miner};
+use toy_blockchain::reward::Schedule;
+use toy_blockchain::transaction::Transaction;
+use toy_blockchain::util::Hashing;
+use toy_blockchain::{util, wallet};
+
+#[test]
+fn sha256_matches_the_standard() {
+    let hash = util::sha256(&util::to_words(&util::pad(b"abc")));
+    assert_eq!(
+        util::hash_to_hex(&hash),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+}
+
+#[tokio::test]
+async fn build_mine_and_verify() {
+    let schedule = Schedule {
+        initial_reward: 1000,
+        halving_interval: 0,
+    };
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let mut chain = Chain::new(LedgerMode::Account, schedule, Hashing::Sha256);
+
+    let coinbase = Transaction::coinbase(0, schedule.reward(0), &key);
+    let payment = Transaction::payment(
+        1,
+        vec![],
+        vec![Output {
+            recipient: "Alice".to_string(),
+            value: 100,
+        }],
+        1,
+        &key,
+    );
+    assert!(payment.verify_signature().is_ok());
+    let block = Block::new(chain.tip_hash(), 1, vec![coinbase, payment]);
+
+    let nonce = CpuMiner
+        .search(
+            &block.words(),
+            block.nonce_offset(),
+            block.k(),
+            block.hashing(),
+            0,
+        )
+        .await;
+    assert_ne!(nonce, 0);
+    let block = block.with_nonce(nonce);
+    assert!(block.meets_target());
+
+    chain.push(block).unwrap();
+    assert!(chain.verify().is_ok());
+    assert_eq!(chain.ledger().balance("Alice"), 100);
+    assert_eq!(
+        chain
+            .ledger()
+            .balance(&wallet::address(&key.verifying_key())),
+        899
+    );
+}
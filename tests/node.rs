@@ -0,0 +1,154 @@
+// several nodes on localhost, talking to each other over tcp like separate processes would
+
+use std::time::Duration;
+
+use ed25519_dalek::SigningKey;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use toy_blockchain::block::Block;
+use toy_blockchain::chain::Chain;
+use toy_blockchain::ledger::{LedgerMode, Output};
+use toy_blockchain::mempool::{Mempool, Order};
+use toy_blockchain::node::{Config, Node, State};
+use toy_blockchain::reward::Schedule;
+use toy_blockchain::transaction::Transaction;
+use toy_blockchain::util::Hashing;
+use toy_blockchain::wallet;
+
+const K: u32 = 1;
+const SCHEDULE: Schedule = Schedule {
+    initial_reward: 1000,
+    halving_interval: 0,
+};
+
+async fn start() -> Node {
+    let state = State {
+        chain: Chain::new(LedgerMode::Account, SCHEDULE, Hashing::Sha256),
+        mempool: Mempool::new(Order::Fee, Duration::from_secs(60)),
+    };
+    let config = Config {
+        min_k: K,
+        chain_path: None,
+    };
+    Node::start("127.0.0.1:0", state, config).await.unwrap()
+}
+
+// k = 1 takes 16 tries on average, no need for the gpu
+fn mine(block: &Block) -> Block {
+    (0..)
+        .map(|nonce| block.with_nonce(nonce))
+        .find(Block::meets_target)
+        .unwrap()
+}
+
+fn mine_on(node: &Node, key: &SigningKey, blocks: usize) {
+    for _ in 0..blocks {
+        node.submit_block(mine(&node.template(K, 16, key))).unwrap();
+    }
+}
+
+fn height(node: &Node) -> u64 {
+    node.state().chain.height()
+}
+
+async fn wait_for(condition: impl Fn() -> bool) {
+    for _ in 0..500 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("timed out");
+}
+
+#[tokio::test]
+async fn nodes_sync_and_gossip() {
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let a = start().await;
+    mine_on(&a, &key, 3);
+
+    // b syncs from a at startup, c syncs from b
+    let b = start().await;
+    b.connect(a.local_addr()).await.unwrap();
+    wait_for(|| height(&b) == 3).await;
+    let c = start().await;
+    c.connect(b.local_addr()).await.unwrap();
+    wait_for(|| height(&c) == 3).await;
+    assert_eq!(c.tip_hash(), a.tip_hash());
+
+    // a new block travels a -> b -> c
+    mine_on(&a, &key, 1);
+    wait_for(|| height(&c) == 4).await;
+    assert_eq!(c.tip_hash(), a.tip_hash());
+
+    // a transaction travels c -> b -> a and gets mined into a's next block
+    let output = Output {
+        recipient: "Alice".to_string(),
+        value: 100,
+    };
+    c.submit_transaction(Transaction::payment(1, vec![], vec![output], 5, &key))
+        .unwrap();
+    wait_for(|| a.template(K, 16, &key).transactions().len() == 2).await;
+    mine_on(&a, &key, 1);
+    wait_for(|| height(&c) == 5).await;
+
+    let state = c.state();
+    assert_eq!(state.chain.ledger().balance("Alice"), 100);
+    assert_eq!(
+        state
+            .chain
+            .ledger()
+            .balance(&wallet::address(&key.verifying_key())),
+        5 * 1000 - 100
+    );
+}
+
+// two nodes mine apart, and once they meet both follow the branch with more work
+#[tokio::test]
+async fn forks_resolve_to_the_most_work() {
+    let a = start().await;
+    let b = start().await;
+    mine_on(&a, &SigningKey::from_bytes(&[1; 32]), 2);
+    mine_on(&b, &SigningKey::from_bytes(&[2; 32]), 3);
+    assert_ne!(a.tip_hash(), b.tip_hash());
+
+    a.connect(b.local_addr()).await.unwrap();
+    wait_for(|| a.tip_hash() == b.tip_hash()).await;
+    assert_eq!(height(&a), 3);
+
+    // the losing side's next block lands on the winning branch and reaches the other node
+    mine_on(&a, &SigningKey::from_bytes(&[1; 32]), 1);
+    wait_for(|| height(&b) == 4).await;
+    assert_eq!(b.tip_hash(), a.tip_hash());
+}
+
+// a peer that skips the version and verack is dropped, and a block it sends is never accepted
+#[tokio::test]
+async fn drops_peers_that_skip_the_handshake() {
+    let node = start().await;
+    let key = SigningKey::from_bytes(&[3; 32]);
+    let iv = Chain::new(LedgerMode::Account, SCHEDULE, Hashing::Sha256).tip_hash();
+    let block = mine(&Block::new(
+        iv,
+        K,
+        vec![Transaction::coinbase(0, 1000, &key)],
+    ));
+
+    let stream = TcpStream::connect(node.local_addr()).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    // the node's version comes first either way
+    let version = lines.next_line().await.unwrap().unwrap();
+    assert!(version.contains("\"version\""), "{version}");
+
+    let message = serde_json::json!({ "type": "block", "block": block });
+    writer
+        .write_all(format!("{message}\n").as_bytes())
+        .await
+        .unwrap();
+    let closed = tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+        .await
+        .unwrap();
+    assert!(matches!(closed, Ok(None) | Err(_)), "{closed:?}");
+    assert_eq!(height(&node), 0);
+}
@@ -0,0 +1,119 @@
+// the hand-written sha256 against the sha2 crate, the way blocks reach it: padded, split into words and
+// with a nonce written over two of them
+
+use proptest::prelude::*;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use sha2::{Digest, Sha256};
+use toy_blockchain::util::{self, Hashing};
+
+fn ours(data: &[u8]) -> String {
+    util::hash_to_hex(&util::sha256(&util::to_words(&util::pad(data))))
+}
+
+fn reference(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn random_bytes(rng: &mut StdRng, len: usize) -> Vec<u8> {
+    let mut data = vec![0; len];
+    rng.fill_bytes(&mut data);
+    data
+}
+
+#[test]
+fn nist_vectors() {
+    for (message, digest) in util::NIST_VECTORS {
+        assert_eq!(ours(message.as_bytes()), digest, "{message:?}");
+        assert_eq!(reference(message.as_bytes()), digest, "{message:?}");
+    }
+    let million = vec![b'a'; 1_000_000];
+    assert_eq!(
+        ours(&million),
+        "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+    );
+}
+
+// 55 bytes is the most that pads to one block, 56 to 64 spill the length into a second one
+#[test]
+fn padding_boundaries() {
+    let mut rng = StdRng::seed_from_u64(56);
+    for len in (0..=200).chain([447, 448, 503, 504, 511, 512]) {
+        let data = random_bytes(&mut rng, len);
+        let padded = util::pad(&data);
+        assert_eq!(padded.len(), (len + 9).div_ceil(64) * 64, "{len} bytes");
+        assert_eq!(ours(&data), reference(&data), "{len} bytes");
+    }
+}
+
+// the nonce has to sit in the message, word aligned: `data` and where in it the nonce goes
+fn block_with_nonce_index(len: std::ops::Range<usize>) -> impl Strategy<Value = (Vec<u8>, usize)> {
+    proptest::collection::vec(any::<u8>(), len).prop_flat_map(|data| {
+        let words = data.len() / 4 - 1;
+        (Just(data), 0..words)
+    })
+}
+
+// nonces anywhere, and around 2^32 where the high word changes
+fn nonce() -> impl Strategy<Value = u64> {
+    prop_oneof![
+        0..1u64 << 32,
+        (1u64 << 32) - 64..(1 << 32) + 64,
+        any::<u64>()
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(500))]
+
+    #[test]
+    fn random_inputs(data in proptest::collection::vec(any::<u8>(), 0..2048)) {
+        prop_assert_eq!(ours(&data), reference(&data));
+    }
+
+    // what the miners hash: the nonce big endian in two words of an otherwise fixed block
+    #[test]
+    fn random_nonces((mut data, nonce_index) in block_with_nonce_index(8..512), nonce in nonce()) {
+        let words = util::to_words(&util::pad(&data));
+        data[nonce_index * 4..nonce_index * 4 + 8].copy_from_slice(&nonce.to_be_bytes());
+        let hash = util::hash_with_nonce(&words, nonce_index as u32, nonce);
+        prop_assert_eq!(util::hash_to_hex(&hash), reference(&data));
+    }
+
+    // sha256d against sha2 twice
+    #[test]
+    fn random_sha256d(data in proptest::collection::vec(any::<u8>(), 0..300)) {
+        let words = util::to_words(&util::pad(&data));
+        prop_assert_eq!(
+            util::hash_to_hex(&Hashing::Sha256d.hash(&words)),
+            hex::encode(Sha256::digest(Sha256::digest(&data)))
+        );
+    }
+}
+
+// sha256d against bitcoin's genesis block header, whose hash is usually shown byte reversed as 000000000019d6...
+#[test]
+fn sha256d() {
+    let header = hex::decode(
+        "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e\
+         67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c",
+    )
+    .unwrap();
+    let words = util::to_words(&util::pad(&header));
+    assert_eq!(
+        util::hash_to_hex(&util::sha256d(&words)),
+        "6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000"
+    );
+}
+
+#[test]
+fn hex_round_trips() {
+    let hash = util::sha256(&util::to_words(&util::pad(b"abc")));
+    let hex = util::hash_to_hex(&hash);
+    assert_eq!(util::hex_to_hash(&hex), Some(hash));
+    assert_eq!(util::hex_to_hash(&hex.to_uppercase()), Some(hash));
+    // from_str_radix takes a sign in front of every word
+    assert_eq!(util::hex_to_hash(&format!("+{}", &hex[1..])), None);
+    assert_eq!(util::hex_to_hash(&hex[1..]), None);
+    assert_eq!(util::hex_to_hash(&format!("{}g", &hex[1..])), None);
+}